@@ -0,0 +1,137 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Auth
+//
+// Optional signing of every datagram with a key shared between the driver's
+// server and the engineers, so that at a LAN event another team's traffic,
+// or anyone else's, can't be shown as our car's data. A signed datagram is
+// the HMAC-SHA256 of its contents in hex, then the contents:
+//
+//     AUTH 3f1c...e9 <the datagram as it would otherwise be sent>
+//
+// This only proves who sent a datagram. Nothing is encrypted, and a signed
+// datagram captured off the network could still be sent again.
+//
+
+use hmac::{ Hmac, Mac };
+use sha2::Sha256;
+
+pub const AUTH_PREFIX: &[u8] = b"AUTH ";
+
+type HmacSha256 = Hmac<Sha256>;
+
+const TAG_HEX_LENGTH: usize = 64;
+
+// What signing adds to each datagram: the prefix, the tag and a space
+pub const AUTH_OVERHEAD: usize = AUTH_PREFIX.len() + TAG_HEX_LENGTH + 1;
+
+#[derive(Clone)]
+pub struct Authenticator {
+    key: Vec<u8>
+}
+
+impl Authenticator {
+    pub fn new(shared_key: &str) -> Authenticator {
+        Authenticator {
+            key: shared_key.as_bytes().to_vec()
+        }
+    }
+
+    pub fn sign(&self, datagram: &[u8]) -> Vec<u8> {
+        let tag: String = self.mac(datagram).finalize().into_bytes().iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        let mut signed = Vec::with_capacity(AUTH_OVERHEAD + datagram.len());
+        signed.extend_from_slice(AUTH_PREFIX);
+        signed.extend_from_slice(tag.as_bytes());
+        signed.push(b' ');
+        signed.extend_from_slice(datagram);
+        signed
+    }
+
+    /// The contents of a datagram signed with our key, or None for one that
+    /// isn't signed or was signed with another key
+    pub fn verify<'a>(&self, datagram: &'a [u8]) -> Option<&'a [u8]> {
+        let signed = datagram.strip_prefix(AUTH_PREFIX)?;
+        if signed.len() <= TAG_HEX_LENGTH || signed[TAG_HEX_LENGTH] != b' ' {
+            return None;
+        }
+
+        let tag = decode_hex(&signed[..TAG_HEX_LENGTH])?;
+        let contents = &signed[TAG_HEX_LENGTH + 1..];
+
+        // Compared in constant time, so the tag can't be guessed a byte at a time
+        self.mac(contents).verify_slice(&tag).ok()?;
+        Some(contents)
+    }
+
+    fn mac(&self, datagram: &[u8]) -> HmacSha256 {
+        // HMAC takes a key of any length
+        let mut mac = HmacSha256::new_from_slice(&self.key).unwrap();
+        mac.update(datagram);
+        mac
+    }
+}
+
+fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    let hex = std::str::from_utf8(hex).ok()?;
+
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_what_it_signed() {
+        let auth = Authenticator::new("shared key");
+        let signed = auth.sign(b"I'm alive!");
+
+        assert_eq!(signed.len(), AUTH_OVERHEAD + b"I'm alive!".len());
+        assert!(signed.starts_with(AUTH_PREFIX));
+        assert_eq!(auth.verify(&signed), Some(&b"I'm alive!"[..]));
+
+        // Empty and binary datagrams too
+        assert_eq!(auth.verify(&auth.sign(b"")), Some(&b""[..]));
+        assert_eq!(auth.verify(&auth.sign(&[0x82, 0x00, 0xff])), Some(&[0x82, 0x00, 0xff][..]));
+    }
+
+    #[test]
+    fn rejects_tampered_datagrams() {
+        let auth = Authenticator::new("shared key");
+        let signed = auth.sign(b"CHUNK 1 0 1\n{}");
+
+        let mut contents = signed.clone();
+        *contents.last_mut().unwrap() = b']';
+        assert!(auth.verify(&contents).is_none());
+
+        // A tag changed in its last hex digit
+        let mut tag = signed;
+        let last_digit = AUTH_PREFIX.len() + TAG_HEX_LENGTH - 1;
+        tag[last_digit] = match tag[last_digit] { b'0' => b'1', _ => b'0' };
+        assert!(auth.verify(&tag).is_none());
+    }
+
+    #[test]
+    fn rejects_other_keys_and_unsigned_datagrams() {
+        let auth = Authenticator::new("shared key");
+        let signed = Authenticator::new("another key").sign(b"DISCOVER");
+        assert!(auth.verify(&signed).is_none());
+
+        assert!(auth.verify(b"DISCOVER").is_none());
+        assert!(auth.verify(b"AUTH ").is_none());
+        assert!(auth.verify(&signed[..AUTH_OVERHEAD - 1]).is_none());
+        assert!(auth.verify(&signed[..AUTH_OVERHEAD + 2]).is_none());
+    }
+
+    #[test]
+    fn decodes_hex() {
+        assert_eq!(decode_hex(b"00ff7A"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(decode_hex(b"0g"), None);
+        assert_eq!(decode_hex(b"abc"), None);
+    }
+}
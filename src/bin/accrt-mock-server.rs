@@ -0,0 +1,438 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Mock ACCRT Server
+//
+// Stands in for ACCRT on the driver's PC, so the dashboard can be developed
+// and demoed without ACC running. Speaks the same UDP handshake, heartbeats
+// and pings, and streams either the generator's car lapping a circuit, with
+// whatever scenario options.yaml sets up for it, or a session recorded with
+// --record. With --chunk-size, packets are split into
+// CHUNK datagrams of at most that many bytes, as a server with a small MTU
+// would send them. Engineers that ask for MessagePack or CBOR get it.
+//
+// Hellos are welcomed with the encoding, rate and field groups this server
+// will send, and packets bigger than the engineer's maximum are chunked.
+// With --legacy hellos are ignored, as ACCRT builds from before them do.
+// Discovery probes are answered with the driver, car and track, and
+// messages for the driver are printed and acknowledged. With --key every
+// datagram is signed with that shared key, and engineers that don't sign
+// theirs with it are ignored.
+//
+// Usage: accrt-mock-server [--port 9100] [--rate 60] [--replay <recording>] [--chunk-size <bytes>] [--legacy]
+//                          [--key <shared key>]
+//
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{ BufRead, BufReader };
+use std::net::{ SocketAddr, UdpSocket };
+use std::time::{ Duration, Instant };
+
+use serde_json::{ json, Value };
+
+use accrt_engineer_rs::auth::{ Authenticator, AUTH_OVERHEAD };
+use accrt_engineer_rs::config;
+use accrt_engineer_rs::discovery::{ ANNOUNCE_PREFIX, DISCOVER_MESSAGE };
+use accrt_engineer_rs::generator::Generator;
+use accrt_engineer_rs::messaging::{ ACK_PREFIX, MESSAGE_PREFIX };
+use accrt_engineer_rs::network::{ self, CLIENT_TIMEOUT, ENCODING_MESSAGE, GOODBYE_MESSAGE, HANDSHAKE_MESSAGE,
+                                  HEARTBEAT_MESSAGE, PING_MESSAGE, PONG_MESSAGE };
+use accrt_engineer_rs::protocol::{ FIELD_GROUPS, HELLO_MESSAGE, PROTOCOL_VERSION, WELCOME_PREFIX };
+use accrt_engineer_rs::reassembly;
+use accrt_engineer_rs::telemetry::Encoding;
+
+const PORT_FLAG: &str = "--port";
+const RATE_FLAG: &str = "--rate";
+const REPLAY_FLAG: &str = "--replay";
+const CHUNK_SIZE_FLAG: &str = "--chunk-size";
+const LEGACY_FLAG: &str = "--legacy";
+const KEY_FLAG: &str = "--key";
+
+const DEFAULT_PORT: u16 = 9100;
+const DEFAULT_RATE_HZ: f64 = 60.0;
+
+const CONTROL_BUFFER_SIZE: usize = 512;
+// Room for the longest chunk header, so every chunk carries some payload
+const MIN_CHUNK_SIZE: usize = 64;
+
+struct Client {
+    last_heard: Instant,
+    encoding: Encoding,
+    field_groups: Option<Vec<String>>, // Everything unless a hello said otherwise
+    max_packet_size: Option<usize>,
+    last_message_id: u32 // Messages are repeated until acknowledged
+}
+
+impl Client {
+    fn new() -> Client {
+        Client {
+            last_heard: Instant::now(),
+            encoding: Encoding::Json,
+            field_groups: None,
+            max_packet_size: None,
+            last_message_id: 0
+        }
+    }
+
+    // Only the groups asked for, each sent as "<group>_data"
+    fn select_groups(&self, packet: &Value) -> Value {
+        let groups = match &self.field_groups {
+            Some(val) => val,
+            None => { return packet.clone(); }
+        };
+
+        let selected = groups.iter()
+            .map(|group| format!("{}_data", group))
+            .filter_map(|key| packet.get(&key).map(|value| (key, value.clone())))
+            .collect();
+        Value::Object(selected)
+    }
+}
+
+trait PacketSource {
+    /// The packet to send at this point since streaming started
+    fn packet_at(&mut self, elapsed: Duration) -> Value;
+}
+
+/// The generator's car, moved on by the time since the last packet. That's
+/// at most one send interval, so the car waits while nobody is connected
+/// rather than jumping ahead by however long that was.
+struct GeneratedSource {
+    generator: Generator,
+    elapsed: Duration,
+    max_step: Duration
+}
+
+impl GeneratedSource {
+    fn new(max_step: Duration) -> GeneratedSource {
+        GeneratedSource {
+            generator: Generator::new(config::load_simulation()),
+            elapsed: Duration::ZERO,
+            max_step
+        }
+    }
+}
+
+impl PacketSource for GeneratedSource {
+    fn packet_at(&mut self, elapsed: Duration) -> Value {
+        let step = elapsed.saturating_sub(self.elapsed).min(self.max_step);
+        self.elapsed = elapsed;
+
+        // Only numbers, strings and string keyed maps, so this can't fail
+        serde_json::to_value(self.generator.next_packet(step)).unwrap()
+    }
+}
+
+/// Plays a recording back at the pace it was recorded, from the top again
+/// once it runs out
+struct RecordedSource {
+    frames: Vec<(u64, Value)>
+}
+
+impl RecordedSource {
+    fn open(path: &str) -> Result<RecordedSource, Box<dyn Error>> {
+        let mut frames: Vec<(u64, Value)> = Vec::new();
+
+        // The first line is the recording header
+        for line in BufReader::new(File::open(path)?).lines().skip(1) {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let mut frame: Value = serde_json::from_str(&line)?;
+            let t_ms = frame["t_ms"].as_u64().ok_or("recording line without t_ms")?;
+            frames.push((t_ms, frame["packet"].take()));
+        }
+
+        if frames.is_empty() {
+            return Err(format!("{} has no packets", path).into());
+        }
+
+        Ok(RecordedSource { frames })
+    }
+}
+
+impl PacketSource for RecordedSource {
+    fn packet_at(&mut self, elapsed: Duration) -> Value {
+        let duration_ms = self.frames.last().map(|(t_ms, _)| *t_ms).unwrap_or(0) + 1;
+        let position_ms = elapsed.as_millis() as u64 % duration_ms;
+
+        let due = self.frames.partition_point(|(t_ms, _)| *t_ms <= position_ms);
+        self.frames[due.saturating_sub(1)].1.clone()
+    }
+}
+
+fn main() {
+    let port = match get_flag_value_from_args(PORT_FLAG) {
+        Some(val) => val.parse::<u16>().unwrap_or_else(|_| exit_with(&format!("Invalid port \"{}\"", val))),
+        None => DEFAULT_PORT
+    };
+
+    let rate = match get_flag_value_from_args(RATE_FLAG) {
+        Some(val) => match val.parse::<f64>() {
+            Ok(rate) if rate > 0.0 => rate,
+            _ => exit_with(&format!("Invalid rate \"{}\"", val))
+        },
+        None => DEFAULT_RATE_HZ
+    };
+
+    let legacy = std::env::args().any(|arg| arg == LEGACY_FLAG);
+
+    let key = get_flag_value_from_args(KEY_FLAG).map(|key| Authenticator::new(&key));
+    // Signing takes room in every datagram, which chunks have to leave for it
+    let overhead = match key {
+        Some(_) => AUTH_OVERHEAD,
+        None => 0
+    };
+
+    let chunk_size = match get_flag_value_from_args(CHUNK_SIZE_FLAG) {
+        Some(val) => match val.parse::<usize>() {
+            Ok(size) if size >= MIN_CHUNK_SIZE + overhead => Some(size - overhead),
+            _ => exit_with(&format!("Invalid chunk size \"{}\", must be at least {}", val, MIN_CHUNK_SIZE + overhead))
+        },
+        None => None
+    };
+
+    let mut source: Box<dyn PacketSource> = match get_flag_value_from_args(REPLAY_FLAG) {
+        Some(path) => match RecordedSource::open(&path) {
+            Ok(val) => Box::new(val),
+            Err(e) => exit_with(&format!("Failed to open recording: {}", e))
+        },
+        None => Box::new(GeneratedSource::new(Duration::from_secs_f64(1.0 / rate)))
+    };
+
+    let socket = match UdpSocket::bind(("0.0.0.0", port)) {
+        Ok(val) => val,
+        Err(e) => exit_with(&format!("Failed to listen on port {}: {}", port, e))
+    };
+
+    println!("Mock ACCRT server listening on port {}, sending at {} Hz", port, rate);
+
+    let interval = Duration::from_secs_f64(1.0 / rate);
+    let started = Instant::now();
+    let mut next_send = Instant::now();
+    let mut clients: HashMap<SocketAddr, Client> = HashMap::new();
+    let mut message_id: u64 = 0;
+    // Discovery is answered from the last packet, there may be nobody to send to
+    let mut latest = source.packet_at(Duration::ZERO);
+
+    loop {
+        handle_control_messages(&socket, key.as_ref(), &mut clients, next_send, legacy, rate, &latest);
+
+        if !clients.is_empty() {
+            latest = source.packet_at(started.elapsed());
+            let packet = &latest;
+            message_id += 1;
+
+            for (address, client) in &clients {
+                let encoded = match client.encoding.encode(&client.select_groups(packet)) {
+                    Ok(val) => val,
+                    Err(e) => exit_with(&format!("Failed to encode a packet as {}: {}", client.encoding, e))
+                };
+                let max_packet_size = client.max_packet_size.map(|max| max.max(MIN_CHUNK_SIZE + overhead) - overhead);
+                let datagrams = match (chunk_size, max_packet_size) {
+                    (Some(size), Some(max)) => split_into_chunks(&encoded, message_id, size.min(max)),
+                    (Some(size), None) => split_into_chunks(&encoded, message_id, size),
+                    (None, Some(max)) if encoded.len() > max => split_into_chunks(&encoded, message_id, max),
+                    _ => vec![encoded]
+                };
+
+                for datagram in &datagrams {
+                    send(&socket, key.as_ref(), datagram, *address);
+                }
+            }
+        }
+
+        // Don't try to catch up on sends missed while the machine was busy
+        next_send = std::cmp::max(next_send + interval, Instant::now());
+    }
+}
+
+// Waits on the socket until the next packet is due, so pings are answered
+// straight away rather than at the send rate
+fn handle_control_messages(socket: &UdpSocket, key: Option<&Authenticator>, clients: &mut HashMap<SocketAddr, Client>,
+                           until: Instant, legacy: bool, rate: f64, latest: &Value) {
+    let mut buffer = [0; CONTROL_BUFFER_SIZE];
+
+    loop {
+        let remaining = until.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining)).unwrap();
+
+        let (buf_size, client) = match socket.recv_from(&mut buffer) {
+            Ok(val) => val,
+            // Timeouts, or an engineer having gone away (ICMP unreachable)
+            Err(_) => continue
+        };
+
+        let datagram = match key {
+            Some(key) => match key.verify(&buffer[..buf_size]) {
+                Some(val) => val,
+                None => {
+                    println!("Ignoring unsigned message from {}", client);
+                    continue;
+                }
+            },
+            None => &buffer[..buf_size]
+        };
+
+        let message = String::from_utf8_lossy(datagram);
+        let message = message.trim();
+
+        if message == DISCOVER_MESSAGE {
+            let statics = &latest["static_data"];
+            let announcement = json!({
+                "driver": format!("{} {}", statics["playerName"].as_str().unwrap_or(""),
+                                  statics["playerSurname"].as_str().unwrap_or("")).trim(),
+                "car": statics["carModel"],
+                "track": statics["track"]
+            });
+            let mut reply = ANNOUNCE_PREFIX.to_vec();
+            reply.extend_from_slice(announcement.to_string().as_bytes());
+            send(socket, key, &reply, client);
+        }
+        else if message == HANDSHAKE_MESSAGE {
+            if !clients.contains_key(&client) {
+                println!("Engineer connected from {}", client);
+            }
+            // A fresh handshake starts back on JSON until asked otherwise
+            clients.insert(client, Client::new());
+        }
+        else if message == GOODBYE_MESSAGE {
+            if clients.remove(&client).is_some() {
+                println!("Engineer at {} said goodbye", client);
+            }
+        }
+        else if message == HEARTBEAT_MESSAGE {
+            clients.entry(client).or_insert_with(Client::new).last_heard = Instant::now();
+        }
+        else if let Some(hello) = message.strip_prefix(HELLO_MESSAGE).filter(|_| !legacy) {
+            match welcome(hello, rate) {
+                Ok((known, reply)) => {
+                    println!("Engineer connected from {} with {}", client, reply);
+                    let mut welcome = WELCOME_PREFIX.to_vec();
+                    welcome.extend_from_slice(reply.to_string().as_bytes());
+                    send(socket, key, &welcome, client);
+                    clients.insert(client, known);
+                },
+                Err(e) => println!("Bad hello from {}: {}", client, e)
+            }
+        }
+        else if let Some(name) = message.strip_prefix(ENCODING_MESSAGE) {
+            match (Encoding::from_name(name.trim()), clients.get_mut(&client)) {
+                (Some(encoding), Some(known)) => {
+                    println!("Engineer at {} asked for {}", client, name.trim());
+                    known.encoding = encoding;
+                },
+                (None, _) => println!("Engineer at {} asked for unknown encoding \"{}\"", client, name.trim()),
+                (Some(_), None) => { }
+            }
+        }
+        else if let Some(ping) = message.strip_prefix(PING_MESSAGE) {
+            let pong = format!("{}{} {}", PONG_MESSAGE, ping, network::unix_ms());
+            send(socket, key, pong.as_bytes(), client);
+        }
+        else if let Some(driver_message) = message.strip_prefix(MESSAGE_PREFIX).and_then(|rest| rest.strip_prefix(' '))
+            .filter(|_| !legacy) {
+            let (id, text) = driver_message.split_once(' ').unwrap_or((driver_message, ""));
+            let id = match id.parse::<u32>() {
+                Ok(val) => val,
+                Err(_) => continue
+            };
+
+            // Repeats are acknowledged again in case the first ack was lost
+            match clients.get_mut(&client) {
+                Some(known) if id <= known.last_message_id => { },
+                Some(known) => {
+                    println!("Message for the driver from {}: {}", client, text);
+                    known.last_message_id = id;
+                },
+                None => println!("Message for the driver from {}: {}", client, text)
+            }
+
+            let mut ack = ACK_PREFIX.to_vec();
+            ack.extend_from_slice(id.to_string().as_bytes());
+            send(socket, key, &ack, client);
+        }
+    }
+
+    clients.retain(|address, client| {
+        let alive = client.last_heard.elapsed() < CLIENT_TIMEOUT;
+        if !alive {
+            println!("Engineer at {} went quiet", address);
+        }
+        alive
+    });
+}
+
+// Settles what an engineer's hello gets: the first encoding both sides
+// know, the groups asked for that exist, and this server's own rate
+fn welcome(hello: &str, rate: f64) -> Result<(Client, Value), Box<dyn Error>> {
+    let hello: Value = serde_json::from_str(hello.trim())?;
+
+    let encoding_names = hello["encodings"].as_array().ok_or("no encodings")?;
+    let (encoding, encoding_name) = encoding_names.iter()
+        .filter_map(|name| name.as_str())
+        .find_map(|name| Encoding::from_name(name).map(|encoding| (encoding, name)))
+        .ok_or("no encoding in common")?;
+
+    let field_groups: Vec<String> = match hello["field_groups"].as_array() {
+        Some(groups) => groups.iter()
+            .filter_map(|group| group.as_str())
+            .filter(|group| FIELD_GROUPS.contains(group))
+            .map(String::from)
+            .collect(),
+        None => FIELD_GROUPS.iter().map(|group| String::from(*group)).collect()
+    };
+    let max_packet_size = hello["max_packet_size"].as_u64().map(|max| max as usize);
+
+    let reply = json!({
+        "protocol": PROTOCOL_VERSION,
+        "server_version": env!("CARGO_PKG_VERSION"),
+        "encoding": encoding_name,
+        "rate_hz": rate,
+        "field_groups": field_groups,
+        "max_packet_size": max_packet_size
+    });
+
+    let client = Client {
+        last_heard: Instant::now(),
+        encoding,
+        field_groups: Some(field_groups),
+        max_packet_size,
+        last_message_id: 0
+    };
+
+    Ok((client, reply))
+}
+
+fn split_into_chunks(packet: &[u8], message_id: u64, chunk_size: usize) -> Vec<Vec<u8>> {
+    match reassembly::split_into_chunks(packet, message_id, chunk_size) {
+        Some(val) => val,
+        None => exit_with(&format!("A {} byte packet doesn't fit in {} chunks of {} bytes",
+                                   packet.len(), reassembly::MAX_CHUNKS, chunk_size))
+    }
+}
+
+fn send(socket: &UdpSocket, key: Option<&Authenticator>, datagram: &[u8], address: SocketAddr) {
+    let _ = match key {
+        Some(key) => socket.send_to(&key.sign(datagram), address),
+        None => socket.send_to(datagram, address)
+    };
+}
+
+fn get_flag_value_from_args(flag: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    let position = args.iter().position(|arg| arg == flag)?;
+
+    args.get(position + 1).cloned()
+}
+
+fn exit_with(message: &str) -> ! {
+    println!("{}. Exiting...", message);
+    std::process::exit(1);
+}
@@ -0,0 +1,295 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Car
+//
+// Everything the engineer knows about one car: where its telemetry comes
+// from, the latest packet, and its own dashboard, so that several cars can
+// be followed at once without their lap history or fuel figures mixing.
+//
+
+use std::time::Instant;
+
+use tui::layout::{ Constraint, Rect };
+use tui::style::{ Modifier, Style };
+use tui::widgets::{ Block, Borders, Paragraph, Row, Table };
+use tui::Frame;
+
+use crate::dashboard::Dashboard;
+use crate::network::ConnectionState;
+use crate::receiver::NetworkReceiver;
+use crate::recorder::SessionRecorder;
+use crate::replay::ReplaySource;
+use crate::telemetry::{ GraphicsData, PhysicsData, StaticData, TelemetryPacket };
+use crate::tui_blocks::TermBackend;
+
+pub enum TelemetrySource {
+    Live(NetworkReceiver),
+    Replay(Box<ReplaySource>)
+}
+
+impl TelemetrySource {
+    pub fn name(&self) -> &str {
+        match self {
+            TelemetrySource::Live(receiver) => &receiver.server_ip,
+            TelemetrySource::Replay(_) => "replay"
+        }
+    }
+}
+
+pub struct Car {
+    pub physics: PhysicsData,
+    pub graphics: GraphicsData,
+    pub statics: StaticData,
+    pub dashboard: Dashboard,
+    pub source: TelemetrySource,
+    parse_error: Option<String>,
+    recorder: Option<SessionRecorder>,
+    static_data_initialized: bool,
+    packet_id: u64, // Of the last packet, 0 outside a session
+    last_packet_at: Option<Instant>
+}
+
+impl Car {
+    pub fn new(source: TelemetrySource, dashboard: Dashboard, recorder: Option<SessionRecorder>) -> Car {
+        Car {
+            physics: PhysicsData::default(),
+            graphics: GraphicsData::default(),
+            statics: StaticData::default(),
+            dashboard,
+            source,
+            parse_error: None,
+            recorder,
+            static_data_initialized: false,
+            packet_id: 0,
+            last_packet_at: None
+        }
+    }
+
+    /// Takes in everything that arrived since the last frame. Every packet
+    /// goes through the dashboard so blocks that track laps don't miss a
+    /// crossing.
+    pub fn update(&mut self) {
+        let receiver = match &mut self.source {
+            TelemetrySource::Live(receiver) => receiver,
+            TelemetrySource::Replay(replay) => {
+                for packet in replay.next_packets() {
+                    self.apply_packet(packet);
+                }
+                return;
+            }
+        };
+
+        let packets = receiver.drain();
+        self.dashboard.update_link(receiver.link_stats());
+
+        for received in packets {
+            let received = match received {
+                Ok(val) => val,
+                Err(e) => {
+                    self.parse_error = Some(e);
+                    continue;
+                }
+            };
+            self.parse_error = None;
+            self.last_packet_at = Some(received.received_at);
+
+            // Nothing is recorded until ACC is actually in a session, so that the
+            // header carries the car and track rather than empty static data
+            if let Some(recorder) = self.recorder.as_mut() {
+                if received.packet.in_session() {
+                    recorder.record(&received.json(), &received.packet.static_data, received.received_at);
+                }
+            }
+
+            self.apply_packet(received.packet);
+        }
+
+        if !self.in_session() {
+            self.static_data_initialized = false;
+        }
+    }
+
+    /// Sends a message to the driver, if there is one to send it to
+    pub fn send_message(&mut self, text: String) {
+        if let TelemetrySource::Live(receiver) = &mut self.source {
+            receiver.send_message(text);
+        }
+    }
+
+    /// Finishes the recording and says goodbye to the server
+    pub fn shutdown(&mut self) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.flush();
+        }
+        if let TelemetrySource::Live(receiver) = &mut self.source {
+            receiver.shutdown();
+        }
+    }
+
+    /// A stale connection keeps showing the last data we received
+    pub fn has_data(&self) -> bool {
+        match &self.source {
+            TelemetrySource::Live(receiver) => {
+                receiver.state() == ConnectionState::Streaming ||
+                receiver.state() == ConnectionState::Stale
+            },
+            TelemetrySource::Replay(_) => true
+        }
+    }
+
+    // TODO instead of this, we need to know when we are actually getting good data
+    pub fn in_session(&self) -> bool {
+        self.has_data() && self.packet_id != 0
+    }
+
+    pub fn name(&self) -> &str {
+        self.source.name()
+    }
+
+    pub fn draw(&self, frame: &mut Frame<TermBackend>, area: Rect) {
+        if self.in_session() {
+            self.dashboard.draw(frame, area);
+            return;
+        }
+
+        let message = match self.has_data() {
+            true => format!("Connection established to {}, waiting for data...", self.name()),
+            false => format!("Requesting data from {}...", self.name())
+        };
+        frame.render_widget(Paragraph::new(message), area);
+    }
+
+    // The connection state and any malformed packet are reported on the
+    // bottom line rather than panicking somewhere inside a block
+    pub fn status(&self) -> String {
+        let mut status = match (&self.source, &self.parse_error) {
+            (TelemetrySource::Live(receiver), Some(e)) => {
+                format!("[{}] Failed to parse telemetry packet: {}", receiver.state(), e)
+            },
+            (TelemetrySource::Live(receiver), None) => {
+                let negotiation = match receiver.negotiation() {
+                    Some(val) => format!(" ({})", val),
+                    None => String::new()
+                };
+                format!("[{}] {}{}{}", receiver.state(), receiver.server_ip, negotiation, self.telemetry_age(receiver))
+            },
+            (TelemetrySource::Replay(replay), _) => replay.status()
+        };
+
+        if let TelemetrySource::Live(receiver) = &self.source {
+            let link = receiver.link_stats();
+            if link.dropped() > 0 {
+                status.push_str(&format!("  DROPPED {} (parse {}, truncated {}, incomplete {}, unsigned {})",
+                                         link.dropped(), link.parse_failures, link.truncated, link.incomplete,
+                                         link.unauthenticated));
+            }
+
+            if let Some(clients) = receiver.relay_clients() {
+                status.push_str(&format!("  RELAY {} clients", clients));
+            }
+
+            if let Some(message) = receiver.last_message() {
+                status.push_str(&format!("  MSG \"{}\" {}", message.text, message.delivery));
+            }
+        }
+
+        if let Some(recorder) = &self.recorder {
+            status.push_str("  ");
+            status.push_str(&recorder.status());
+        }
+
+        status
+    }
+
+    fn connection_state(&self) -> String {
+        match &self.source {
+            TelemetrySource::Live(receiver) => receiver.state().to_string(),
+            TelemetrySource::Replay(_) => String::from("Replay")
+        }
+    }
+
+    fn apply_packet(&mut self, packet: TelemetryPacket) {
+        self.packet_id = packet.packet_id();
+        self.physics = packet.physics_data;
+        self.graphics = packet.graphics_data;
+        self.statics = packet.static_data;
+
+        if self.packet_id == 0 {
+            return;
+        }
+
+        if !self.static_data_initialized {
+            self.dashboard.init_statics(&self.statics);
+            self.static_data_initialized = true;
+        }
+
+        self.dashboard.update(&self.physics, &self.graphics);
+    }
+
+    // How old the data on screen is: the time since it arrived, plus half
+    // the round trip for the time it spent getting here, once that's known
+    fn telemetry_age(&self, receiver: &NetworkReceiver) -> String {
+        let arrived = match self.last_packet_at {
+            Some(val) => val,
+            None => { return String::new(); }
+        };
+
+        let age_ms = arrived.elapsed().as_secs_f64() * 1000.0;
+        match receiver.link_stats().rtt_ms {
+            Some(rtt_ms) => format!("  age {:.0} ms (RTT {:.0} ms)", age_ms + rtt_ms / 2.0, rtt_ms),
+            None => format!("  age {:.0} ms", age_ms)
+        }
+    }
+}
+
+/// One row per car with the few numbers an endurance engineer glances at,
+/// the car on the dashboard highlighted
+pub fn draw_summary(frame: &mut Frame<TermBackend>, area: Rect, cars: &[Car], current: usize) {
+    let header = Row::new(vec!["#", "Server", "State", "Driver", "Pos", "Laps", "Last", "Best", "Fuel", "Pit"])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+
+    let rows: Vec<Row> = cars.iter().enumerate()
+        .map(|(index, car)| {
+            let row = match car.in_session() {
+                true => Row::new(vec![
+                    (index + 1).to_string(),
+                    car.name().to_string(),
+                    car.connection_state(),
+                    car.statics.player_surname.clone(),
+                    car.graphics.position.to_string(),
+                    car.graphics.completed_laps.to_string(),
+                    car.graphics.last_time.clone(),
+                    car.graphics.best_time.clone(),
+                    format!("{:.1}", car.physics.fuel),
+                    String::from(if car.graphics.is_in_pit { "PIT" } else { "" })
+                ]),
+                false => Row::new(vec![(index + 1).to_string(), car.name().to_string(), car.connection_state()])
+            };
+
+            match index == current {
+                true => row.style(Style::default().add_modifier(Modifier::REVERSED)),
+                false => row
+            }
+        })
+        .collect();
+
+    let widths = [
+        Constraint::Length(2),
+        Constraint::Length(21),
+        Constraint::Length(12),
+        Constraint::Length(12),
+        Constraint::Length(3),
+        Constraint::Length(4),
+        Constraint::Length(9),
+        Constraint::Length(9),
+        Constraint::Length(5),
+        Constraint::Length(3)
+    ];
+
+    let table = Table::new(rows)
+        .header(header)
+        .widths(&widths)
+        .block(Block::default().borders(Borders::ALL).title("Cars"));
+
+    frame.render_widget(table, area);
+}
@@ -3,22 +3,118 @@
 //
 
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use crossterm::event;
 use std::error::Error;
 use std::fs::File;
 use std::io::Read;
+use std::path::PathBuf;
 use std::str::FromStr;
 
+use crate::messaging;
+use crate::protocol::FIELD_GROUPS;
+use crate::telemetry::Encoding;
+
 const CONFIG_FILE_PATH: &str = "src/cfg/options.yaml";
 const CONFIG_FILE_MAX_BUFFER_SIZE: usize = 0x4000; // 64 KB
+const DEFAULT_RECORDING_DIRECTORY: &str = "recordings";
+const DEFAULT_FUEL_SAFETY_MARGIN_LAPS: f64 = 1.0;
+const DEFAULT_FRAME_RATE: u64 = 60;
+// The largest payload a UDP datagram can carry over IPv4
+const DEFAULT_MAX_PACKET_SIZE: usize = 65507;
+const MIN_PACKET_SIZE: usize = 512;
+const DEFAULT_ENCODING: Encoding = Encoding::Json;
+const DEFAULT_DISCOVERY_TARGET: &str = "255.255.255.255:9100";
+
+const DEFAULT_SIMULATION_RATE_HZ: f64 = 60.0;
+const DEFAULT_SIMULATION_LAP_TIME_S: f64 = 105.0;
+const DEFAULT_SIMULATION_SESSION_S: f64 = 3600.0;
+// Wheel names in the order ACC lists them
+const WHEEL_NAMES: [&str; 4] = ["FL", "FR", "RL", "RR"];
+
+const DEFAULT_PAGE_NAME: &str = "Main";
+const DEFAULT_LAYOUT_COLUMNS: usize = 2;
+const DEFAULT_COLUMN_WIDTH: u16 = 28;
+const DEFAULT_BLOCKS: [&str; 6] = [
+    "tachometer", "lap_times",
+    "tyre_temps", "thermometer",
+    "brake_temps", "tyre_pressures"
+];
+
+/// A block entry from the `blocks:` list. Blocks without a position flow
+/// into the grid in the order they are listed.
+pub struct BlockConfig {
+    pub name: String,
+    pub position: Option<(u16, u16)>,
+    pub width: Option<u16>,
+    pub height: Option<u16>
+}
+
+impl BlockConfig {
+    fn from_name(name: &str) -> BlockConfig {
+        BlockConfig {
+            name: String::from(name),
+            position: None,
+            width: None,
+            height: None
+        }
+    }
+}
+
+/// A user-defined block from the `custom_blocks:` section
+pub struct CustomBlockConfig {
+    pub name: String,
+    pub title: String,
+    pub position: Option<(u16, u16)>,
+    pub width: Option<u16>,
+    pub physics_keys: Vec<String>,
+    pub graphics_keys: Vec<String>,
+    pub format_str: String
+}
+
+/// Something scripted to happen to the generated car, from `simulation: events:`
+#[derive(Debug, Clone, Copy)]
+pub enum ScenarioEvent {
+    Puncture { lap: u32, wheel: usize },
+    Rain { lap: u32, laps: u32 }
+}
+
+/// Settings for the telemetry generator, from the `simulation:` section
+pub struct SimulationConfig {
+    pub rate_hz: f64,
+    pub lap_time_s: f64,
+    pub session_length_s: f64,
+    pub events: Vec<ScenarioEvent>
+}
 
-pub struct HotkeyFunction {
-    function: fn(),
+/// A dashboard page from the `pages:` section. Without one there is a single
+/// page holding the top level `blocks:` list.
+pub struct PageConfig {
+    pub name: String,
+    pub hotkey: Option<char>,
+    pub blocks: Vec<BlockConfig>
+}
+
+pub struct LayoutConfig {
+    pub columns: usize,
+    pub column_width: u16,
+    pub pages: Vec<PageConfig>,
+    pub custom_blocks: Vec<CustomBlockConfig>
+}
+
+/// A call sent to the driver with a single key
+pub struct PresetMessage {
+    pub text: String,
+    pub hotkey: char
+}
+
+pub struct HotkeyFunction<F> {
+    function: F,
     name: String
 }
 
-impl HotkeyFunction {
-    pub fn new(name_str: &str, function: fn()) -> HotkeyFunction {
+impl<F> HotkeyFunction<F> {
+    pub fn new(name_str: &str, function: F) -> HotkeyFunction<F> {
         let name: String = String::from(name_str);
 
         HotkeyFunction {
@@ -28,8 +124,8 @@ impl HotkeyFunction {
     }
 }
 
-pub fn build_hotkeys(functions: Vec<HotkeyFunction>) -> HashMap<event::Event, fn()> {
-    let mut hotkeys: HashMap<event::Event, fn()> = HashMap::new();
+pub fn build_hotkeys<F: Copy>(functions: Vec<HotkeyFunction<F>>) -> HashMap<event::Event, F> {
+    let mut hotkeys: HashMap<event::Event, F> = HashMap::new();
 
     let yaml = match load_yaml_file() {
         Ok(val) => val,
@@ -49,7 +145,7 @@ pub fn build_hotkeys(functions: Vec<HotkeyFunction>) -> HashMap<event::Event, fn
         hotkeys.insert(build_key_event(hotkey_char), entry.function);
     }
 
-    return hotkeys;
+    hotkeys
 }
 
 fn convert_yaml_str_to_char(value: &serde_yaml::Value) -> Option<char> {
@@ -74,10 +170,331 @@ fn load_yaml_file() -> Result<serde_yaml::Value, Box<dyn Error>> {
 }
 
 // TODO: Can potentially add support for key modifiers
-fn build_key_event(hotkey: char) -> event::Event {
+pub fn build_key_event(hotkey: char) -> event::Event {
     event::Event::Key(event::KeyEvent {
         code: event::KeyCode::Char(hotkey),
         modifiers: event::KeyModifiers::NONE
     })
 }
 
+
+pub fn load_recording_directory() -> PathBuf {
+    let yaml = match load_yaml_file() {
+        Ok(val) => val,
+        Err(_) => { return PathBuf::from(DEFAULT_RECORDING_DIRECTORY); }
+    };
+
+    match yaml["recording"]["directory"].as_str() {
+        Some(val) => PathBuf::from(val),
+        None => PathBuf::from(DEFAULT_RECORDING_DIRECTORY)
+    }
+}
+
+/// The largest datagram the engineer accepts, anything bigger is counted
+/// as truncated
+pub fn load_max_packet_size() -> usize {
+    let yaml = match load_yaml_file() {
+        Ok(val) => val,
+        Err(_) => { return DEFAULT_MAX_PACKET_SIZE; }
+    };
+
+    match yaml["network"]["max_packet_size"].as_u64() {
+        Some(val) => std::cmp::max(val as usize, MIN_PACKET_SIZE),
+        None => DEFAULT_MAX_PACKET_SIZE
+    }
+}
+
+/// The encoding to ask the server for: json, msgpack or cbor
+pub fn load_encoding() -> Encoding {
+    let yaml = match load_yaml_file() {
+        Ok(val) => val,
+        Err(_) => { return DEFAULT_ENCODING; }
+    };
+
+    match yaml["network"]["encoding"].as_str().and_then(Encoding::from_name) {
+        Some(val) => val,
+        None => DEFAULT_ENCODING
+    }
+}
+
+/// The packet rate to ask the server for, or None to leave it to the server
+pub fn load_requested_rate() -> Option<f64> {
+    let yaml = load_yaml_file().ok()?;
+
+    match yaml["network"]["rate_hz"].as_f64() {
+        Some(val) if val > 0.0 => Some(val),
+        _ => None
+    }
+}
+
+/// The groups of channels to ask the server for, all of them unless the
+/// config lists some. Unknown names are left out.
+pub fn load_field_groups() -> Vec<String> {
+    let all_groups = || FIELD_GROUPS.iter().map(|group| String::from(*group)).collect();
+
+    let yaml = match load_yaml_file() {
+        Ok(val) => val,
+        Err(_) => { return all_groups(); }
+    };
+
+    let groups: Vec<String> = match yaml["network"]["field_groups"].as_sequence() {
+        Some(val) => val.iter()
+            .filter_map(|group| group.as_str())
+            .filter(|group| FIELD_GROUPS.contains(group))
+            .map(String::from)
+            .collect(),
+        None => { return all_groups(); }
+    };
+
+    match groups.is_empty() {
+        true => all_groups(),
+        false => groups
+    }
+}
+
+/// Where to listen for the server, or None to listen on every interface
+pub fn load_listen_address() -> Option<String> {
+    let yaml = load_yaml_file().ok()?;
+
+    yaml["network"]["listen"].as_str().map(String::from)
+}
+
+/// The key shared with the driver's server for signing packets, or None
+/// to send and accept them unsigned
+pub fn load_shared_key() -> Option<String> {
+    let yaml = load_yaml_file().ok()?;
+
+    match yaml["network"]["shared_key"].as_str() {
+        Some(val) if !val.is_empty() => Some(String::from(val)),
+        _ => None
+    }
+}
+
+/// Where to look for servers when none is given on the command line
+pub fn load_discovery_targets() -> Vec<String> {
+    let default_targets = || vec![String::from(DEFAULT_DISCOVERY_TARGET)];
+
+    let yaml = match load_yaml_file() {
+        Ok(val) => val,
+        Err(_) => { return default_targets(); }
+    };
+
+    match yaml["discovery"]["targets"].as_sequence() {
+        Some(val) => val.iter().filter_map(|target| target.as_str()).map(String::from).collect(),
+        None => default_targets()
+    }
+}
+
+/// Text is cleaned up the same as a typed message. Entries without both
+/// text and a hotkey are skipped.
+pub fn load_preset_messages() -> Vec<PresetMessage> {
+    let yaml = match load_yaml_file() {
+        Ok(val) => val,
+        Err(_) => { return Vec::new(); }
+    };
+
+    let entries = match yaml["driver_messages"].as_sequence() {
+        Some(val) => val,
+        None => { return Vec::new(); }
+    };
+
+    entries.iter()
+        .filter_map(|entry| Some(PresetMessage {
+            text: messaging::clean_message(entry["text"].as_str()?)?,
+            hotkey: convert_yaml_str_to_char(&entry["hotkey"])?
+        }))
+        .collect()
+}
+
+/// How many times a second the dashboard is redrawn
+pub fn load_frame_rate() -> u64 {
+    let yaml = match load_yaml_file() {
+        Ok(val) => val,
+        Err(_) => { return DEFAULT_FRAME_RATE; }
+    };
+
+    match yaml["display"]["frame_rate"].as_u64() {
+        Some(val) if val > 0 => val,
+        _ => DEFAULT_FRAME_RATE
+    }
+}
+
+pub fn load_simulation() -> SimulationConfig {
+    let mut simulation = SimulationConfig {
+        rate_hz: DEFAULT_SIMULATION_RATE_HZ,
+        lap_time_s: DEFAULT_SIMULATION_LAP_TIME_S,
+        session_length_s: DEFAULT_SIMULATION_SESSION_S,
+        events: Vec::new()
+    };
+
+    let yaml = match load_yaml_file() {
+        Ok(val) => val,
+        Err(_) => { return simulation; }
+    };
+    let section = &yaml["simulation"];
+
+    if let Some(val) = section["rate"].as_f64().filter(|val| *val > 0.0) {
+        simulation.rate_hz = val;
+    }
+    if let Some(val) = section["lap_time"].as_f64().filter(|val| *val > 0.0) {
+        simulation.lap_time_s = val;
+    }
+    if let Some(val) = section["session_length"].as_f64().filter(|val| *val > 0.0) {
+        simulation.session_length_s = val;
+    }
+    if let Some(events) = section["events"].as_sequence() {
+        simulation.events = events.iter().filter_map(parse_scenario_event).collect();
+    }
+
+    simulation
+}
+
+// Events that are misspelled or missing their lap are skipped rather than
+// stopping the simulation from starting
+fn parse_scenario_event(entry: &serde_yaml::Value) -> Option<ScenarioEvent> {
+    let lap = u32::try_from(entry["lap"].as_u64()?).ok()?;
+
+    match entry["type"].as_str()? {
+        "puncture" => {
+            let wheel = entry["wheel"].as_str().unwrap_or("FL");
+            let wheel = WHEEL_NAMES.iter().position(|name| name.eq_ignore_ascii_case(wheel))?;
+            Some(ScenarioEvent::Puncture { lap, wheel })
+        },
+        "rain" => {
+            let laps = entry["laps"].as_u64().and_then(|val| u32::try_from(val).ok()).unwrap_or(1);
+            Some(ScenarioEvent::Rain { lap, laps })
+        },
+        _ => None
+    }
+}
+
+/// Extra laps of fuel the fuel calculator adds on top of what it thinks is needed
+pub fn load_fuel_safety_margin() -> f64 {
+    let yaml = match load_yaml_file() {
+        Ok(val) => val,
+        Err(_) => { return DEFAULT_FUEL_SAFETY_MARGIN_LAPS; }
+    };
+
+    match yaml["fuel"]["safety_margin_laps"].as_f64() {
+        Some(val) if val >= 0.0 => val,
+        _ => DEFAULT_FUEL_SAFETY_MARGIN_LAPS
+    }
+}
+
+pub fn load_layout() -> LayoutConfig {
+    let default_page = |blocks: Vec<BlockConfig>| PageConfig {
+        name: String::from(DEFAULT_PAGE_NAME),
+        hotkey: None,
+        blocks
+    };
+    let default_blocks = || DEFAULT_BLOCKS.iter().map(|name| BlockConfig::from_name(name)).collect();
+
+    let yaml = match load_yaml_file() {
+        Ok(val) => val,
+        Err(_) => {
+            return LayoutConfig {
+                columns: DEFAULT_LAYOUT_COLUMNS,
+                column_width: DEFAULT_COLUMN_WIDTH,
+                pages: vec![default_page(default_blocks())],
+                custom_blocks: Vec::new()
+            };
+        }
+    };
+
+    let columns = match yaml["layout"]["columns"].as_u64() {
+        Some(val) if val > 0 => val as usize,
+        _ => DEFAULT_LAYOUT_COLUMNS
+    };
+
+    let column_width = yaml_u16(&yaml["layout"]["column_width"]).unwrap_or(DEFAULT_COLUMN_WIDTH);
+
+    let pages = match yaml["pages"].as_sequence() {
+        Some(entries) => entries.iter().filter_map(parse_page_entry).collect(),
+        None => match yaml["blocks"].as_sequence() {
+            Some(entries) => vec![default_page(entries.iter().filter_map(parse_block_entry).collect())],
+            None => vec![default_page(default_blocks())]
+        }
+    };
+
+    let custom_blocks = match yaml["custom_blocks"].as_mapping() {
+        Some(entries) => entries.iter()
+            .filter_map(|(name, entry)| parse_custom_block_entry(name.as_str()?, entry))
+            .collect(),
+        None => Vec::new()
+    };
+
+    LayoutConfig { columns, column_width, pages, custom_blocks }
+}
+
+fn parse_page_entry(entry: &serde_yaml::Value) -> Option<PageConfig> {
+    let blocks = match entry["blocks"].as_sequence() {
+        Some(entries) => entries.iter().filter_map(parse_block_entry).collect(),
+        None => Vec::new()
+    };
+
+    Some(PageConfig {
+        name: String::from(entry["name"].as_str()?),
+        hotkey: convert_yaml_str_to_char(&entry["hotkey"]),
+        blocks
+    })
+}
+
+fn parse_custom_block_entry(name: &str, entry: &serde_yaml::Value) -> Option<CustomBlockConfig> {
+    if entry["active"].as_bool() == Some(false) {
+        return None;
+    }
+
+    let position = match (yaml_u16(&entry["start_x"]), yaml_u16(&entry["start_y"])) {
+        (None, None) => None,
+        (x, y) => Some((x.unwrap_or(0), y.unwrap_or(0)))
+    };
+
+    Some(CustomBlockConfig {
+        name: String::from(name),
+        title: String::from(entry["title"].as_str().unwrap_or(name)),
+        position,
+        width: yaml_u16(&entry["width"]),
+        physics_keys: yaml_str_list(&entry["physics_keys"]),
+        graphics_keys: yaml_str_list(&entry["graphics_keys"]),
+        format_str: String::from(entry["format_str"].as_str().unwrap_or(""))
+    })
+}
+
+fn yaml_str_list(value: &serde_yaml::Value) -> Vec<String> {
+    match value.as_sequence() {
+        Some(items) => items.iter().filter_map(|item| item.as_str()).map(String::from).collect(),
+        None => Vec::new()
+    }
+}
+
+// An entry is either just the block's name or a mapping with the name and
+// optional placement. Entries marked `active: false` are left off the screen.
+fn parse_block_entry(entry: &serde_yaml::Value) -> Option<BlockConfig> {
+    if let Some(name) = entry.as_str() {
+        return Some(BlockConfig::from_name(name));
+    }
+
+    if entry["active"].as_bool() == Some(false) {
+        return None;
+    }
+
+    let name = entry["name"].as_str()?;
+
+    let x = yaml_u16(&entry["x"]);
+    let y = yaml_u16(&entry["y"]);
+    let position = match (x, y) {
+        (None, None) => None,
+        (x, y) => Some((x.unwrap_or(0), y.unwrap_or(0)))
+    };
+
+    Some(BlockConfig {
+        name: String::from(name),
+        position,
+        width: yaml_u16(&entry["width"]),
+        height: yaml_u16(&entry["height"])
+    })
+}
+
+fn yaml_u16(value: &serde_yaml::Value) -> Option<u16> {
+    value.as_u64().and_then(|val| u16::try_from(val).ok())
+}
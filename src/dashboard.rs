@@ -0,0 +1,295 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Dashboard
+//
+
+use std::collections::{ HashMap, HashSet };
+
+use crossterm::event;
+use tui::layout::{ Constraint, Direction, Layout, Rect };
+use tui::style::{ Modifier, Style };
+use tui::text::Spans;
+use tui::widgets::Tabs;
+use tui::Frame;
+
+use crate::config::{ self, BlockConfig, LayoutConfig };
+use crate::network::LinkStats;
+use crate::telemetry::{ GraphicsData, PhysicsData, StaticData };
+use crate::tui_blocks::{ self, CustomBlock, TUIBlock, TermBackend };
+
+// Pages can always be reached with the number keys, in the order listed
+const PAGE_NUMBER_KEYS: [char; 9] = ['1', '2', '3', '4', '5', '6', '7', '8', '9'];
+
+struct PlacedBlock {
+    block: Box<dyn TUIBlock>,
+    position: Option<(u16, u16)>,
+    width: Option<u16>,
+    height: Option<u16>
+}
+
+impl PlacedBlock {
+    fn height(&self) -> u16 {
+        self.height.unwrap_or_else(|| self.block.height())
+    }
+}
+
+/// A named set of blocks and where they go
+struct Page {
+    name: String,
+    blocks: Vec<PlacedBlock>,
+    columns: usize,
+    column_width: u16
+}
+
+impl Page {
+    fn draw(&self, frame: &mut Frame<TermBackend>, area: Rect) {
+        self.draw_grid(frame, area);
+
+        for placed in self.blocks.iter() {
+            if let Some((x, y)) = placed.position {
+                let block_area = Rect {
+                    x: area.x.saturating_add(x),
+                    y: area.y.saturating_add(y),
+                    width: placed.width.unwrap_or(self.column_width),
+                    height: placed.height()
+                };
+
+                // Pinned blocks are clipped to the screen rather than wrapped,
+                // and not drawn at all once they are off it
+                if let Some(block_area) = clip(block_area, area) {
+                    placed.block.display(frame, block_area);
+                }
+            }
+        }
+    }
+
+    // Unpinned blocks fill the grid left to right, each row as tall as the
+    // tallest block in it
+    fn draw_grid(&self, frame: &mut Frame<TermBackend>, area: Rect) {
+        let flowing: Vec<&PlacedBlock> = self.blocks.iter()
+            .filter(|placed| placed.position.is_none())
+            .collect();
+        let rows: Vec<&[&PlacedBlock]> = flowing.chunks(self.columns).collect();
+
+        let mut row_constraints: Vec<Constraint> = rows.iter()
+            .map(|row| Constraint::Length(row.iter().map(|placed| placed.height()).max().unwrap_or(0)))
+            .collect();
+        row_constraints.push(Constraint::Min(0));
+
+        let row_areas = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(row_constraints)
+            .split(area);
+
+        let mut column_constraints = vec![Constraint::Length(self.column_width); self.columns];
+        column_constraints.push(Constraint::Min(0));
+
+        for (row, row_area) in rows.iter().zip(row_areas) {
+            let column_areas = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints(column_constraints.clone())
+                .split(row_area);
+
+            for (placed, cell) in row.iter().zip(column_areas) {
+                let block_area = Rect {
+                    width: placed.width.map_or(cell.width, |width| width.min(cell.width)),
+                    height: placed.height().min(cell.height),
+                    ..cell
+                };
+                placed.block.display(frame, block_area);
+            }
+        }
+    }
+}
+
+/// Every page of blocks, and which one is on screen
+pub struct Dashboard {
+    pages: Vec<Page>,
+    current: usize,
+    page_hotkeys: HashMap<event::Event, usize>
+}
+
+impl Dashboard {
+    pub fn from_config(layout: &LayoutConfig) -> Result<Dashboard, String> {
+        let mut placed_custom: HashSet<&str> = HashSet::new();
+        let mut pages: Vec<Page> = Vec::new();
+        let mut page_hotkeys: HashMap<event::Event, usize> = HashMap::new();
+
+        for (index, page_config) in layout.pages.iter().enumerate() {
+            let mut blocks: Vec<PlacedBlock> = Vec::new();
+
+            for entry in page_config.blocks.iter() {
+                // Custom blocks can be placed on a page by name like any other,
+                // and each placement gets its own copy as blocks keep state
+                let block: Box<dyn TUIBlock> = match layout.custom_blocks.iter().find(|custom| custom.name == entry.name) {
+                    Some(custom) => {
+                        placed_custom.insert(&custom.name);
+                        Box::new(CustomBlock::new(custom)?)
+                    },
+                    None => build_block(entry)?
+                };
+
+                blocks.push(PlacedBlock {
+                    block,
+                    position: entry.position,
+                    width: entry.width,
+                    height: entry.height
+                });
+            }
+
+            pages.push(Page {
+                name: page_config.name.clone(),
+                blocks,
+                columns: layout.columns,
+                column_width: layout.column_width
+            });
+
+            if let Some(key) = PAGE_NUMBER_KEYS.get(index) {
+                page_hotkeys.insert(config::build_key_event(*key), index);
+            }
+            if let Some(key) = page_config.hotkey {
+                page_hotkeys.insert(config::build_key_event(key), index);
+            }
+        }
+
+        if pages.is_empty() {
+            return Err(String::from("No dashboard pages in options.yaml"));
+        }
+
+        // Custom blocks that aren't on any page go on the first one
+        for custom in layout.custom_blocks.iter().filter(|custom| !placed_custom.contains(custom.name.as_str())) {
+            pages[0].blocks.push(PlacedBlock {
+                block: Box::new(CustomBlock::new(custom)?),
+                position: custom.position,
+                width: custom.width,
+                height: None
+            });
+        }
+
+        Ok(Dashboard {
+            pages,
+            current: 0,
+            page_hotkeys
+        })
+    }
+
+    /// Switches page if the event is one of the page hotkeys
+    pub fn handle_page_hotkey(&mut self, event: &event::Event) {
+        if let Some(index) = self.page_hotkeys.get(event) {
+            self.current = *index;
+        }
+    }
+
+    pub fn next_page(&mut self) {
+        self.current = (self.current + 1) % self.pages.len();
+    }
+
+    pub fn previous_page(&mut self) {
+        self.current = (self.current + self.pages.len() - 1) % self.pages.len();
+    }
+
+    /// Scrolls every scrollable block on the page on screen
+    pub fn scroll(&mut self, lines: i32) {
+        for placed in self.pages[self.current].blocks.iter_mut() {
+            placed.block.scroll(lines);
+        }
+    }
+
+    pub fn init_statics(&mut self, statics: &StaticData) {
+        for placed in self.blocks_mut() {
+            placed.block.init_statics(statics);
+        }
+    }
+
+    // Pages that aren't on screen are still updated so blocks that track
+    // history don't miss anything
+    pub fn update(&mut self, physics: &PhysicsData, graphics: &GraphicsData) {
+        for placed in self.blocks_mut() {
+            placed.block.update(physics, graphics);
+        }
+    }
+
+    pub fn update_link(&mut self, stats: &LinkStats) {
+        for placed in self.blocks_mut() {
+            placed.block.update_link(stats);
+        }
+    }
+
+    pub fn draw(&self, frame: &mut Frame<TermBackend>, area: Rect) {
+        let page = &self.pages[self.current];
+
+        if self.pages.len() == 1 {
+            page.draw(frame, area);
+            return;
+        }
+
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(1), Constraint::Min(0)])
+            .split(area);
+
+        let titles: Vec<Spans> = self.pages.iter().enumerate()
+            .map(|(index, page)| Spans::from(format!("{} {}", index + 1, page.name)))
+            .collect();
+
+        let tabs = Tabs::new(titles)
+            .select(self.current)
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED | Modifier::BOLD));
+
+        frame.render_widget(tabs, chunks[0]);
+        page.draw(frame, chunks[1]);
+    }
+
+    fn blocks_mut(&mut self) -> impl Iterator<Item = &mut PlacedBlock> {
+        self.pages.iter_mut().flat_map(|page| page.blocks.iter_mut())
+    }
+}
+
+fn build_block(entry: &BlockConfig) -> Result<Box<dyn TUIBlock>, String> {
+    match tui_blocks::build_block(&entry.name) {
+        Some(block) => Ok(block),
+        None => {
+            let available: Vec<&str> = tui_blocks::block_registry().iter()
+                .map(|(name, _)| *name)
+                .collect();
+            Err(format!("Unknown block \"{}\" in options.yaml, available blocks are: {}",
+                        entry.name, available.join(", ")))
+        }
+    }
+}
+
+// The part of block_area inside area, if any. Rect::intersection subtracts
+// without saturating, so it can't be used on blocks off the screen.
+fn clip(block_area: Rect, area: Rect) -> Option<Rect> {
+    let x = block_area.x.max(area.x);
+    let y = block_area.y.max(area.y);
+    let right = block_area.x.saturating_add(block_area.width).min(area.x.saturating_add(area.width));
+    let bottom = block_area.y.saturating_add(block_area.height).min(area.y.saturating_add(area.height));
+
+    let width = right.saturating_sub(x);
+    let height = bottom.saturating_sub(y);
+    match width > 0 && height > 0 {
+        true => Some(Rect { x, y, width, height }),
+        false => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clips_pinned_blocks_to_the_screen() {
+        let screen = Rect { x: 0, y: 1, width: 80, height: 23 };
+
+        let inside = Rect { x: 10, y: 5, width: 30, height: 6 };
+        assert_eq!(clip(inside, screen), Some(inside));
+
+        let overhanging = Rect { x: 60, y: 20, width: 30, height: 6 };
+        assert_eq!(clip(overhanging, screen), Some(Rect { x: 60, y: 20, width: 20, height: 4 }));
+
+        assert_eq!(clip(Rect { x: 100, y: 5, width: 30, height: 6 }, screen), None);
+        assert_eq!(clip(Rect { x: 10, y: 30, width: 30, height: 6 }, screen), None);
+        assert_eq!(clip(Rect { x: u16::MAX, y: u16::MAX, width: 30, height: 6 }, screen), None);
+    }
+}
@@ -0,0 +1,156 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Discovery
+//
+// Finds ACCRT servers on the local network so nobody has to look up the
+// driver's IP address. The engineer broadcasts "DISCOVER" and every server
+// that hears it answers with where to connect and who is driving:
+//
+//     ANNOUNCE {"port":9100,"driver":"Jane Doe","car":"ferrari_296_gt3","track":"monza"}
+//
+// The port is optional, the one the answer came from is used without it.
+// With a shared key the probe is signed, and only servers that answer
+// signed with the same key are listed.
+//
+
+use std::io;
+use std::net::{ SocketAddr, ToSocketAddrs, UdpSocket };
+use std::time::{ Duration, Instant };
+
+use serde::Deserialize;
+
+use crate::auth::Authenticator;
+
+pub const DISCOVER_MESSAGE: &str = "DISCOVER";
+pub const ANNOUNCE_PREFIX: &[u8] = b"ANNOUNCE ";
+
+const PROBE_INTERVAL: Duration = Duration::from_millis(1000);
+// A server that stops answering drops off the list after this long
+const SERVER_TIMEOUT: Duration = Duration::from_millis(5000);
+const ANNOUNCE_BUFFER_SIZE: usize = 1024;
+
+#[derive(Deserialize)]
+struct Announcement {
+    #[serde(default)]
+    port: Option<u16>,
+    #[serde(default)]
+    driver: String,
+    #[serde(default)]
+    car: String,
+    #[serde(default)]
+    track: String
+}
+
+#[derive(Debug, Clone)]
+pub struct DiscoveredServer {
+    pub address: SocketAddr,
+    pub driver: String,
+    pub car: String,
+    pub track: String,
+    last_heard: Instant
+}
+
+pub struct Discovery {
+    socket: UdpSocket,
+    targets: Vec<SocketAddr>,
+    servers: Vec<DiscoveredServer>,
+    last_probe: Option<Instant>,
+    auth: Option<Authenticator>
+}
+
+impl Discovery {
+    /// Targets are where to send the probe, normally the broadcast address
+    /// with the port servers listen on. Ones that don't resolve are skipped.
+    pub fn bind(targets: &[String]) -> io::Result<Discovery> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+
+        let targets = targets.iter()
+            .filter_map(|target| target.to_socket_addrs().ok())
+            .flatten()
+            .collect();
+
+        Ok(Discovery {
+            socket,
+            targets,
+            servers: Vec::new(),
+            last_probe: None,
+            auth: None
+        })
+    }
+
+    pub fn with_authenticator(mut self, auth: Option<Authenticator>) -> Discovery {
+        self.auth = auth;
+        self
+    }
+
+    /// Servers heard from recently, in the order they were first found
+    pub fn servers(&self) -> &[DiscoveredServer] {
+        &self.servers
+    }
+
+    /// Probes again if it is time to, and takes in whatever has answered
+    pub fn poll(&mut self) {
+        let probe_due = match self.last_probe {
+            Some(sent) => sent.elapsed() >= PROBE_INTERVAL,
+            None => true
+        };
+
+        if probe_due {
+            let probe = match &self.auth {
+                Some(auth) => auth.sign(DISCOVER_MESSAGE.as_bytes()),
+                None => DISCOVER_MESSAGE.as_bytes().to_vec()
+            };
+
+            // Unreachable targets are simply not heard from
+            for target in &self.targets {
+                let _ = self.socket.send_to(&probe, target);
+            }
+            self.last_probe = Some(Instant::now());
+        }
+
+        let mut buffer = [0; ANNOUNCE_BUFFER_SIZE];
+        while let Ok((buf_size, from)) = self.socket.recv_from(&mut buffer) {
+            // Servers without the key aren't ours to connect to
+            let datagram = match &self.auth {
+                Some(auth) => match auth.verify(&buffer[..buf_size]) {
+                    Some(val) => val,
+                    None => continue
+                },
+                None => &buffer[..buf_size]
+            };
+
+            if let Some(server) = parse_announcement(datagram, from) {
+                self.on_server(server);
+            }
+        }
+
+        self.servers.retain(|server| server.last_heard.elapsed() < SERVER_TIMEOUT);
+    }
+
+    fn on_server(&mut self, server: DiscoveredServer) {
+        match self.servers.iter_mut().find(|known| known.address == server.address) {
+            Some(known) => *known = server,
+            None => self.servers.push(server)
+        }
+    }
+}
+
+fn parse_announcement(datagram: &[u8], from: SocketAddr) -> Option<DiscoveredServer> {
+    let body = datagram.strip_prefix(ANNOUNCE_PREFIX)?;
+    let announcement: Announcement = serde_json::from_slice(body).ok()?;
+
+    let mut address = from;
+    if let Some(port) = announcement.port {
+        address.set_port(port);
+    }
+
+    Some(DiscoveredServer {
+        address,
+        driver: announcement.driver,
+        car: announcement.car,
+        track: announcement.track,
+        last_heard: Instant::now()
+    })
+}
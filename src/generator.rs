@@ -0,0 +1,363 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Generator
+//
+// Makes up plausible telemetry for a car lapping a virtual track, with tyres
+// and brakes warming up, fuel burning, and whatever punctures or rain the
+// scenario in options.yaml asks for. Used to exercise the dashboard under
+// conditions that are hard to arrange in ACC.
+//
+
+use std::time::Duration;
+
+use serde_json::{ Map, Value };
+
+use crate::config::{ ScenarioEvent, SimulationConfig };
+use crate::telemetry::{ FlagType, GraphicsData, PhysicsData, SessionType, StaticData, Status,
+                        TelemetryPacket, Wheels };
+
+const SECTOR_COUNT: u32 = 3;
+const MAX_RPM: u64 = 8000;
+const MAX_FUEL: f64 = 120.0;
+const START_FUEL: f64 = 60.0;
+const FUEL_PER_LAP: f64 = 2.8;
+const AIR_TEMP: f64 = 22.0;
+const DRY_ROAD_TEMP: f64 = 30.0;
+const WET_ROAD_TEMP: f64 = 21.0;
+
+// How much slower a lap is, as a fraction of the dry lap time
+const RAIN_SLOWDOWN: f64 = 0.08;
+const PUNCTURE_SLOWDOWN: f64 = 0.12;
+// Lap to lap variation, as a fraction of the lap time
+const LAP_TIME_SPREAD: f64 = 0.005;
+
+// Tyre temperatures settle towards their target over this many seconds,
+// and a punctured tyre goes down over this many
+const TYRE_WARMUP_S: f64 = 20.0;
+const DEFLATE_S: f64 = 8.0;
+const BRAKE_COOLING_S: f64 = 6.0;
+
+// Longer steps are taken in slices of at most this, so a car moved on a long
+// way at once still crosses every line and warms up gradually on the way
+const MAX_STEP_S: f64 = 0.1;
+
+// ACC's value for medium rain in graphics rainIntensity
+const RAIN_INTENSITY: u32 = 3;
+
+pub struct Generator {
+    simulation: SimulationConfig,
+    rng: u64,
+    packet_id: u64,
+    elapsed_s: f64,
+    progress: f64, // Fraction of the current lap driven
+    lap_pace: f64, // How much slower than the configured lap time this lap is
+    lap_elapsed_ms: f64,
+    completed_laps: u32,
+    sector_index: u32,
+    sector_start_ms: f64,
+    last_sector_ms: i64,
+    last_lap_ms: i64,
+    best_lap_ms: i64,
+    fuel: f64,
+    fuel_at_lap_start: f64,
+    fuel_x_lap: f64,
+    tyre_temp: Wheels<f64>,
+    deflation: Wheels<f64>, // 0 is fully inflated, 1 is flat
+    brake_temp: Wheels<f64>,
+    road_temp: f64
+}
+
+impl Generator {
+    pub fn new(simulation: SimulationConfig) -> Generator {
+        let mut generator = Generator {
+            simulation,
+            rng: 0x2545_f491_4f6c_dd1d,
+            packet_id: 0,
+            elapsed_s: 0.0,
+            progress: 0.0,
+            lap_pace: 1.0,
+            lap_elapsed_ms: 0.0,
+            completed_laps: 0,
+            sector_index: 0,
+            sector_start_ms: 0.0,
+            last_sector_ms: 0,
+            last_lap_ms: 0,
+            best_lap_ms: 0,
+            fuel: START_FUEL,
+            fuel_at_lap_start: START_FUEL,
+            fuel_x_lap: FUEL_PER_LAP,
+            tyre_temp: [AIR_TEMP + 10.0; 4],
+            deflation: [0.0; 4],
+            brake_temp: [AIR_TEMP + 100.0; 4],
+            road_temp: DRY_ROAD_TEMP
+        };
+        generator.lap_pace = generator.random_pace();
+
+        generator
+    }
+
+    pub fn rate_hz(&self) -> f64 {
+        self.simulation.rate_hz
+    }
+
+    /// Moves the car on by `step` and describes where it is now
+    pub fn next_packet(&mut self, step: Duration) -> TelemetryPacket {
+        let mut remaining_s = step.as_secs_f64();
+        while remaining_s > 0.0 {
+            let dt = remaining_s.min(MAX_STEP_S);
+            self.advance(dt);
+            remaining_s -= dt;
+        }
+
+        self.packet_id += 1;
+
+        let raining = self.is_raining();
+        let (straight, braking) = self.corner_profile();
+        let speed = (80.0 + 180.0 * straight) * if raining { 0.9 } else { 1.0 };
+
+        let gear = ((speed / 45.0) as u64 + 1).min(6);
+        let gear_band = ((speed - 45.0 * (gear - 1) as f64) / 45.0).clamp(0.0, 1.0);
+        let rpms = (MAX_RPM as f64 * (0.6 + 0.35 * gear_band)) as u64;
+
+        let physics = PhysicsData {
+            packet_id: self.packet_id,
+            gas: straight,
+            brake: braking,
+            fuel: self.fuel,
+            // ACC counts reverse and neutral as gears 0 and 1
+            gear: (gear + 1) as u8,
+            rpms,
+            speed_kmh: speed,
+            tyre_temp: self.tyre_temp,
+            wheels_pressure: self.pressures(),
+            brake_temp: self.brake_temp,
+            air_temp: AIR_TEMP,
+            road_temp: self.road_temp,
+            other_channels: channel("waterTemp", Value::from(88.0))
+        };
+
+        let graphics = GraphicsData {
+            packet_id: self.packet_id,
+            status: Status::Live,
+            session: SessionType::Race,
+            current_time: format_lap_time(self.lap_elapsed_ms as i64),
+            last_time: format_lap_time(self.last_lap_ms),
+            best_time: format_lap_time(self.best_lap_ms),
+            completed_laps: self.completed_laps,
+            position: 1,
+            i_current_time: self.lap_elapsed_ms as i64,
+            i_last_time: self.last_lap_ms,
+            i_best_time: self.best_lap_ms,
+            session_time_left: ((self.simulation.session_length_s - self.elapsed_s) * 1000.0).max(0.0),
+            is_in_pit: false,
+            current_sector_index: self.sector_index,
+            last_sector_time: self.last_sector_ms,
+            number_of_laps: 0,
+            normalized_car_position: self.progress,
+            flag: FlagType::None,
+            fuel_x_lap: self.fuel_x_lap,
+            used_fuel: START_FUEL - self.fuel,
+            is_valid_lap: true,
+            other_channels: channel("rainIntensity", Value::from(if raining { RAIN_INTENSITY } else { 0 }))
+        };
+
+        let statics = StaticData {
+            car_model: String::from("synthetic_gt3"),
+            track: String::from("virtual_circuit"),
+            player_name: String::from("Synthetic"),
+            player_surname: String::from("Driver"),
+            sector_count: SECTOR_COUNT,
+            max_rpm: MAX_RPM,
+            max_fuel: MAX_FUEL
+        };
+
+        TelemetryPacket {
+            physics_data: physics,
+            graphics_data: graphics,
+            static_data: statics
+        }
+    }
+
+    fn advance(&mut self, dt: f64) {
+        let raining = self.is_raining();
+        let punctured = self.punctured_wheel();
+
+        self.elapsed_s += dt;
+        self.lap_elapsed_ms += dt * 1000.0;
+
+        let mut lap_time_s = self.simulation.lap_time_s * self.lap_pace;
+        if raining {
+            lap_time_s *= 1.0 + RAIN_SLOWDOWN;
+        }
+        if punctured.is_some() {
+            lap_time_s *= 1.0 + PUNCTURE_SLOWDOWN;
+        }
+        self.progress += dt / lap_time_s;
+        self.fuel = (self.fuel - FUEL_PER_LAP * dt / lap_time_s).max(0.0);
+
+        if self.progress >= 1.0 {
+            // The line was crossed part way through the step
+            let past_line_ms = (self.progress - 1.0) * lap_time_s * 1000.0;
+            self.complete_lap(past_line_ms);
+        }
+        else {
+            let sector = ((self.progress * SECTOR_COUNT as f64) as u32).min(SECTOR_COUNT - 1);
+            if sector != self.sector_index {
+                self.last_sector_ms = (self.lap_elapsed_ms - self.sector_start_ms) as i64;
+                self.sector_start_ms = self.lap_elapsed_ms;
+                self.sector_index = sector;
+            }
+        }
+
+        let (straight, braking) = self.corner_profile();
+        self.update_temperatures(dt, straight, braking, raining, punctured);
+    }
+
+    // Four straights and four corners a lap, 0 in the slowest part of a
+    // corner and 1 at the end of a straight, with braking into each corner
+    fn corner_profile(&self) -> (f64, f64) {
+        let straight = (self.progress * std::f64::consts::PI * 8.0).sin().abs();
+        let braking = if straight < 0.2 { 1.0 - straight * 5.0 } else { 0.0 };
+        (straight, braking)
+    }
+
+    // Laps are counted from 1, as the scenario is written
+    fn current_lap(&self) -> u32 {
+        self.completed_laps + 1
+    }
+
+    fn is_raining(&self) -> bool {
+        let lap = self.current_lap();
+        self.simulation.events.iter().any(|event| match event {
+            ScenarioEvent::Rain { lap: start, laps } => lap >= *start && lap < start + laps,
+            _ => false
+        })
+    }
+
+    fn punctured_wheel(&self) -> Option<usize> {
+        let lap = self.current_lap();
+        self.simulation.events.iter().find_map(|event| match event {
+            ScenarioEvent::Puncture { lap: start, wheel } if lap >= *start => Some(*wheel),
+            _ => None
+        })
+    }
+
+    fn complete_lap(&mut self, past_line_ms: f64) {
+        self.progress -= 1.0;
+
+        let lap_elapsed_ms = self.lap_elapsed_ms - past_line_ms;
+        let lap_ms = lap_elapsed_ms as i64;
+        self.last_sector_ms = (lap_elapsed_ms - self.sector_start_ms) as i64;
+        self.last_lap_ms = lap_ms;
+        if self.best_lap_ms == 0 || lap_ms < self.best_lap_ms {
+            self.best_lap_ms = lap_ms;
+        }
+
+        self.fuel_x_lap = self.fuel_at_lap_start - self.fuel;
+        self.fuel_at_lap_start = self.fuel;
+
+        self.completed_laps += 1;
+        self.lap_elapsed_ms = past_line_ms;
+        self.sector_start_ms = 0.0;
+        self.sector_index = 0;
+        self.lap_pace = self.random_pace();
+    }
+
+    fn update_temperatures(&mut self, dt: f64, straight: f64, braking: f64, raining: bool,
+                           punctured: Option<usize>) {
+        let road_target = if raining { WET_ROAD_TEMP } else { DRY_ROAD_TEMP };
+        self.road_temp += (road_target - self.road_temp) * dt / TYRE_WARMUP_S;
+
+        for wheel in 0..4 {
+            // Fronts work harder than rears, and a flat tyre overheats
+            let mut target = if raining { 62.0 } else { 84.0 + 8.0 * (1.0 - straight) };
+            if wheel < 2 {
+                target += 3.0;
+            }
+            if punctured == Some(wheel) {
+                self.deflation[wheel] += (1.0 - self.deflation[wheel]) * dt / DEFLATE_S;
+                target += 15.0;
+            }
+            self.tyre_temp[wheel] += (target - self.tyre_temp[wheel]) * dt / TYRE_WARMUP_S;
+
+            let brake_heat = if wheel < 2 { 700.0 } else { 450.0 };
+            self.brake_temp[wheel] += braking * brake_heat * dt
+                - (self.brake_temp[wheel] - (AIR_TEMP + 250.0)) * dt / BRAKE_COOLING_S;
+        }
+    }
+
+    // Hot pressures follow tyre temperature, about 27.5 psi when at temperature
+    fn pressures(&self) -> Wheels<f64> {
+        [0, 1, 2, 3].map(|wheel| {
+            let inflated = 20.0 + self.tyre_temp[wheel] * 0.085;
+            inflated * (1.0 - 0.8 * self.deflation[wheel])
+        })
+    }
+
+    // xorshift, enough to make each lap a little different
+    fn random_pace(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+
+        let unit = (self.rng % 10000) as f64 / 10000.0;
+        1.0 + (unit * 2.0 - 1.0) * LAP_TIME_SPREAD
+    }
+}
+
+// The few channels generated beyond what PhysicsData and GraphicsData model
+fn channel(name: &str, value: Value) -> Map<String, Value> {
+    let mut channels = Map::new();
+    channels.insert(String::from(name), value);
+    channels
+}
+
+fn format_lap_time(ms: i64) -> String {
+    let ms = ms.max(0);
+    format!("{}:{:02}.{:03}", ms / 60000, (ms / 1000) % 60, ms % 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator(lap_time_s: f64) -> Generator {
+        Generator::new(SimulationConfig {
+            rate_hz: 60.0,
+            lap_time_s,
+            session_length_s: 3600.0,
+            events: Vec::new()
+        })
+    }
+
+    #[test]
+    fn long_steps_match_many_short_ones() {
+        let mut stepped = generator(5.0);
+        let mut packet = stepped.next_packet(Duration::ZERO);
+        for _ in 0..22 * 60 {
+            packet = stepped.next_packet(Duration::from_secs_f64(1.0 / 60.0));
+        }
+
+        let mut jumped = generator(5.0);
+        let jump = jumped.next_packet(Duration::from_secs(22));
+
+        // Every lap on the way is completed, each taking about 5 s
+        assert_eq!(jump.graphics_data.completed_laps, 4);
+        assert_eq!(jump.graphics_data.completed_laps, packet.graphics_data.completed_laps);
+        assert!((jump.graphics_data.i_best_time - 5000).abs() < 50);
+        assert!((jump.graphics_data.i_last_time - 5000).abs() < 50);
+        assert!((jump.physics_data.fuel - packet.physics_data.fuel).abs() < 0.1);
+
+        // Temperatures settle on the way instead of overshooting
+        for wheel in 0..4 {
+            assert!((jump.physics_data.tyre_temp[wheel] - packet.physics_data.tyre_temp[wheel]).abs() < 2.0);
+            assert!(jump.physics_data.brake_temp[wheel] > AIR_TEMP);
+            assert!(jump.physics_data.brake_temp[wheel] < 1000.0);
+        }
+
+        // And the next packet goes on from there as normal
+        let next = jumped.next_packet(Duration::from_secs_f64(1.0 / 60.0));
+        assert_eq!(next.graphics_data.completed_laps, 4);
+        assert_eq!(next.graphics_data.i_best_time, jump.graphics_data.i_best_time);
+    }
+}
@@ -0,0 +1,26 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Lib
+//
+// Everything the engineer is built from, shared with the mock server in
+// src/bin so both ends speak the protocol from the same constants, encodings
+// and signing rather than copies of them.
+//
+
+pub mod auth;
+pub mod car;
+pub mod config;
+pub mod dashboard;
+pub mod discovery;
+pub mod generator;
+pub mod messaging;
+pub mod network;
+pub mod picker;
+pub mod protocol;
+pub mod reassembly;
+pub mod receiver;
+pub mod recorder;
+pub mod relay;
+pub mod replay;
+pub mod telemetry;
+pub mod tui_blocks;
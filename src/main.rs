@@ -3,240 +3,621 @@
 // Main
 //
 
-use crossterm::{ cursor, event, terminal };
+use crossterm::event;
 use std::collections::HashMap;
-
-mod tui_blocks;
-mod config;
-use crate::tui_blocks::*;
-
-const BUFFER_SIZE: usize = 8192;
-const HEARTBEAT_DELTA_IN_MS: std::time::Duration = std::time::Duration::from_millis(2000);
-const LISTEN_IP_ADDR_PORT: &str = "0.0.0.0:9001";
-
-// TODO: Should polling rate be a part of telemetry parser?
-//       this would move the thread sleep functions into
-//       TelemetryParser the object
-const POLLING_RATE_IN_MS: u64 = 16; // Roughly 60 Hz
-
-struct NetworkInfo {
-    socket:    std::net::UdpSocket,
-    server_ip: String,
-    _listen_ip: String,
-    heartbeat: std::time::SystemTime
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use tui::layout::{ Constraint, Direction, Layout };
+use tui::widgets::Paragraph;
+use tui::{ Frame, Terminal };
+
+use accrt_engineer_rs::{ car, config, messaging, picker, replay };
+use accrt_engineer_rs::tui_blocks::TermBackend;
+use accrt_engineer_rs::auth::Authenticator;
+use accrt_engineer_rs::car::{ Car, TelemetrySource };
+use accrt_engineer_rs::dashboard::Dashboard;
+use accrt_engineer_rs::discovery::Discovery;
+use accrt_engineer_rs::generator::Generator;
+use accrt_engineer_rs::network::NetworkInfo;
+use accrt_engineer_rs::protocol::Hello;
+use accrt_engineer_rs::receiver::NetworkReceiver;
+use accrt_engineer_rs::recorder::SessionRecorder;
+use accrt_engineer_rs::relay::Relay;
+use accrt_engineer_rs::replay::ReplaySource;
+use std::time::{ Duration, Instant };
+
+// Each car is listened for on its own port, counting up from this one,
+// unless --listen or the config says otherwise
+const LISTEN_BASE_PORT: u16 = 9001;
+const LISTEN_FLAG: &str = "--listen";
+const RECORD_FLAG: &str = "--record";
+const REPLAY_FLAG: &str = "--replay";
+const RELAY_FLAG: &str = "--relay";
+const SIMULATE_FLAG: &str = "--simulate";
+
+// Flags that take the following argument as their value
+const VALUE_FLAGS: [&str; 3] = [LISTEN_FLAG, REPLAY_FLAG, RELAY_FLAG];
+
+// How long the network thread waits on the socket before checking on the
+// connection state, independent of how often the UI redraws
+const RECV_TIMEOUT: Duration = Duration::from_millis(50);
+
+// Set from the signal handler, or a panic on another thread, neither of
+// which can touch the terminal or the cars itself, and acted on by the main loop
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+// The exit code a shell expects after Ctrl+C
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+// The exit code Rust gives a panic
+const PANIC_EXIT_CODE: i32 = 101;
+
+type HotkeyAction = fn(&mut TelemetryParser);
+
+// What the text typed into the bottom line is for
+#[derive(Clone, Copy)]
+enum PromptKind {
+    Jump,
+    Message
 }
 
-impl NetworkInfo {
-    fn new(listen_ip: String, server_ip: String) -> NetworkInfo {
-        NetworkInfo {
-            socket: std::net::UdpSocket::bind(&listen_ip).unwrap(),
-            server_ip,
-            _listen_ip: listen_ip,
-            heartbeat: std::time::SystemTime::now()
-        }
-    }
+struct Prompt {
+    kind: PromptKind,
+    input: String
 }
 
 struct TelemetryParser {
-    physics: serde_json::Value,
-    graphics: serde_json::Value,
-    statics: serde_json::Value, 
-    blocks: Vec<Box<dyn TUIBlock>>,
-    hotkeys: HashMap<event::Event, fn()>,
-    network: NetworkInfo
+    cars: Vec<Car>,
+    current_car: usize,
+    show_summary: bool,
+    hotkeys: HashMap<event::Event, HotkeyAction>,
+    preset_messages: HashMap<event::Event, String>,
+    prompt: Option<Prompt>,
+    frame_duration: Duration,
+    // What another thread panicked with, printed once the terminal is back
+    thread_panic: Arc<Mutex<Option<String>>>
 }
 
 impl TelemetryParser {
     // TODO: Consider making this non looping
     fn main(&mut self) {
-        let mut static_data_initialized: bool = false;
+        let mut terminal = Terminal::new(TermBackend::new(std::io::stdout())).unwrap();
 
         loop {
+            let frame_start = Instant::now();
+
+            if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+                self.shutdown(INTERRUPTED_EXIT_CODE);
+            }
+
             self.handle_keypress();
 
-            match self.update_telemetry_from_connection() {
-                Ok(()) => { },
-                Err(_e) => { 
-                    sleep_for_polling_rate();
-                    continue;
-                }
-            };
+            for car in self.cars.iter_mut() {
+                car.update();
+            }
 
-            println!("{}", terminal::Clear(terminal::ClearType::All));
+            terminal.draw(|frame| self.draw(frame)).unwrap();
 
-            // TODO instead of this, we need to know when we are actually getting good data
-            if self.physics["packetId"] != 0 {
-                if !static_data_initialized {
-                    self.init_vector_statics();
-                    static_data_initialized = true;
-                }
+            if let Some(remaining) = self.frame_duration.checked_sub(frame_start.elapsed()) {
+                std::thread::sleep(remaining);
+            }
+        }
+    }
 
-                for block in self.blocks.iter_mut() {
-                    block.update(&self.physics, &self.graphics);
-                    block.display();
-                }
+    fn new(cars: Vec<Car>, thread_panic: Arc<Mutex<Option<String>>>) -> TelemetryParser {
+        TelemetryParser {
+            cars,
+            current_car: 0,
+            show_summary: false,
+            hotkeys: TelemetryParser::generate_hotkeys_from_config(),
+            preset_messages: config::load_preset_messages().into_iter()
+                .map(|preset| (config::build_key_event(preset.hotkey), preset.text))
+                .collect(),
+            prompt: None,
+            frame_duration: Duration::from_secs_f64(1.0 / config::load_frame_rate() as f64),
+            thread_panic
+        }
+    }
+
+    fn generate_hotkeys_from_config() -> HashMap<event::Event, HotkeyAction> {
+        let function_map: Vec<config::HotkeyFunction<HotkeyAction>> = vec![
+            config::HotkeyFunction::new("exit_terminal", TelemetryParser::exit_terminal),
+            config::HotkeyFunction::new("next_page", TelemetryParser::next_page),
+            config::HotkeyFunction::new("previous_page", TelemetryParser::previous_page),
+            config::HotkeyFunction::new("scroll_up", TelemetryParser::scroll_up),
+            config::HotkeyFunction::new("scroll_down", TelemetryParser::scroll_down),
+            config::HotkeyFunction::new("next_car", TelemetryParser::next_car),
+            config::HotkeyFunction::new("car_summary", TelemetryParser::car_summary),
+            config::HotkeyFunction::new("replay_pause", TelemetryParser::replay_pause),
+            config::HotkeyFunction::new("replay_speed_up", TelemetryParser::replay_speed_up),
+            config::HotkeyFunction::new("replay_slow_down", TelemetryParser::replay_slow_down),
+            config::HotkeyFunction::new("replay_next_lap", TelemetryParser::replay_next_lap),
+            config::HotkeyFunction::new("replay_jump", TelemetryParser::replay_jump),
+            config::HotkeyFunction::new("send_message", TelemetryParser::send_message)
+        ];
+
+        config::build_hotkeys(function_map)
+    }
+
+    fn handle_keypress(&mut self) {
+        while TelemetryParser::is_event_available() {
+            let event = event::read().unwrap();
+
+            // Raw mode turns Ctrl+C into a key rather than a signal
+            if is_interrupt(&event) {
+                self.shutdown(INTERRUPTED_EXIT_CODE);
+            }
+
+            if self.prompt.is_some() {
+                self.handle_prompt_keypress(event);
+                continue;
+            }
+
+            if let Some(function) = self.hotkeys.get(&event).copied() {
+                function(self);
+            }
+            else if let Some(text) = self.preset_messages.get(&event).cloned() {
+                self.car_mut().send_message(text);
             }
             else {
-                println!("{}{}", terminal::Clear(terminal::ClearType::All) ,cursor::MoveTo(0,0));
-                println!("Connection established to {}, waiting for data...", self.network.server_ip);
-                static_data_initialized = false;
+                self.car_mut().dashboard.handle_page_hotkey(&event);
             }
+        }
+    }
+
+    // While a prompt is open keys are typed into it instead of triggering
+    // hotkeys. Enter jumps or sends, Escape cancels.
+    fn handle_prompt_keypress(&mut self, event: event::Event) {
+        let key = match event {
+            event::Event::Key(key) => key,
+            _ => { return; }
+        };
 
-            self.send_heartbeat_to_server();
-            sleep_for_polling_rate();
+        match key.code {
+            event::KeyCode::Char(c) => {
+                if let Some(prompt) = self.prompt.as_mut() {
+                    prompt.input.push(c);
+                }
+            },
+            event::KeyCode::Backspace => {
+                if let Some(prompt) = self.prompt.as_mut() {
+                    prompt.input.pop();
+                }
+            },
+            event::KeyCode::Enter => {
+                let prompt = match self.prompt.take() {
+                    Some(val) => val,
+                    None => { return; }
+                };
+
+                match prompt.kind {
+                    PromptKind::Jump => {
+                        if let (Some(target_ms), TelemetrySource::Replay(replay)) =
+                                (replay::parse_timestamp(&prompt.input), &mut self.car_mut().source) {
+                            replay.seek(target_ms);
+                        }
+                    },
+                    PromptKind::Message => {
+                        if let Some(text) = messaging::clean_message(&prompt.input) {
+                            self.car_mut().send_message(text);
+                        }
+                    }
+                }
+            },
+            event::KeyCode::Esc => self.prompt = None,
+            _ => { }
         }
     }
 
-    fn new(listen_ip_addr: String, server_ip_addr: String) -> TelemetryParser {
-        return TelemetryParser {
-            physics: serde_json::Value::Null,
-            graphics: serde_json::Value::Null,
-            statics: serde_json::Value::Null,
-            blocks: TelemetryParser::generate_blocks(),
-            hotkeys: TelemetryParser::generate_hotkeys_from_config(),
-            network: NetworkInfo::new(listen_ip_addr, server_ip_addr)
+    fn exit_terminal(&mut self) {
+        self.shutdown(0);
+    }
+
+    /// The one way out: says goodbye to every server, finishes the
+    /// recordings and gives the terminal back before exiting
+    fn shutdown(&mut self, code: i32) -> ! {
+        for car in self.cars.iter_mut() {
+            car.shutdown();
+        }
+        terminal_cleanup();
+
+        match self.thread_panic.lock().ok().and_then(|mut panic| panic.take()) {
+            Some(message) => {
+                println!("{}", message);
+                std::process::exit(PANIC_EXIT_CODE);
+            },
+            None => std::process::exit(code)
         }
     }
 
-    fn generate_blocks() -> Vec<Box<dyn TUIBlock>> {
-        let blocks: Vec<Box<dyn TUIBlock>> = vec![
-            Box::new(tui_blocks::Tachometer::new(0,0)),
-            Box::new(tui_blocks::TyreTemps::new(0,6)),
-            Box::new(tui_blocks::LapTimes::new(24,0)),
-            Box::new(tui_blocks::Thermometer::new(24,6)),
-            Box::new(tui_blocks::BrakeTemps::new(0,12)),
-            Box::new(tui_blocks::TyrePressures::new(24,12))
-        ];
+    fn next_page(&mut self) {
+        self.car_mut().dashboard.next_page();
+    }
 
-        return blocks;
+    fn previous_page(&mut self) {
+        self.car_mut().dashboard.previous_page();
     }
 
-    fn generate_hotkeys_from_config() -> HashMap<event::Event, fn()> {
-        let function_map: Vec<config::HotkeyFunction> = vec![
-            config::HotkeyFunction::new("exit_terminal", exit_terminal)
-        ];
+    fn scroll_up(&mut self) {
+        self.car_mut().dashboard.scroll(-1);
+    }
 
-        return config::build_hotkeys(function_map);
+    fn scroll_down(&mut self) {
+        self.car_mut().dashboard.scroll(1);
     }
 
-    fn handle_keypress(&self) {
-        if TelemetryParser::is_event_available() {
-            match self.hotkeys.get(&event::read().unwrap()) {
-                Some(function) => function(),
-                None => { }
-            }
+    fn next_car(&mut self) {
+        self.current_car = (self.current_car + 1) % self.cars.len();
+    }
+
+    fn car_summary(&mut self) {
+        self.show_summary = !self.show_summary;
+    }
+
+    fn replay_pause(&mut self) {
+        if let TelemetrySource::Replay(replay) = &mut self.car_mut().source {
+            replay.toggle_pause();
         }
     }
 
-    fn preconnect_setup(&self) {
-        println!("Sending request for data to {}", &self.network.server_ip);
+    fn replay_speed_up(&mut self) {
+        if let TelemetrySource::Replay(replay) = &mut self.car_mut().source {
+            replay.speed_up();
+        }
+    }
 
-        match self.network.socket.send_to("Give me the data!".as_bytes(), &self.network.server_ip) {
-            Ok(_size) => { },
-            Err(_e) => panic!("Send request for data failed!")
-        };
+    fn replay_slow_down(&mut self) {
+        if let TelemetrySource::Replay(replay) = &mut self.car_mut().source {
+            replay.slow_down();
+        }
+    }
 
-        self.wait_for_initial_message();
+    fn replay_next_lap(&mut self) {
+        if let TelemetrySource::Replay(replay) = &mut self.car_mut().source {
+            replay.skip_to_next_lap();
+        }
     }
 
-    fn update_telemetry_from_connection(&mut self) -> Result<(), serde_json::Error> {
-        let mut buffer = [0; BUFFER_SIZE];
-        let buf_len: usize = match self.network.socket.recv(&mut buffer) {
-            Ok(buf_size) => buf_size,
-            Err(e)       => panic!("{}", e)
-        };
+    fn replay_jump(&mut self) {
+        if let TelemetrySource::Replay(_) = &self.car().source {
+            self.prompt = Some(Prompt { kind: PromptKind::Jump, input: String::new() });
+        }
+    }
 
-        let json_data: serde_json::Value = serde_json::from_slice(&buffer[0..buf_len])?;
+    fn send_message(&mut self) {
+        if let TelemetrySource::Live(_) = &self.car().source {
+            self.prompt = Some(Prompt { kind: PromptKind::Message, input: String::new() });
+        }
+    }
 
-        // TODO Still find a better way to do this
-        self.physics = json_data["physics_data"].clone();
-        self.graphics = json_data["graphics_data"].clone();
-        self.statics = json_data["static_data"].clone();
+    fn car(&self) -> &Car {
+        &self.cars[self.current_car]
+    }
 
-        Ok(())
+    fn car_mut(&mut self) -> &mut Car {
+        &mut self.cars[self.current_car]
     }
 
-    fn send_heartbeat_to_server(&mut self) {
-        let current_time = std::time::SystemTime::now();
-        
-        if current_time.duration_since(self.network.heartbeat).unwrap() > HEARTBEAT_DELTA_IN_MS {
-            self.network.socket.send_to("I'm alive!".as_bytes(), &self.network.server_ip).unwrap();
-            self.network.heartbeat = current_time;
+    fn draw(&self, frame: &mut Frame<TermBackend>) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(0), Constraint::Length(1)])
+            .split(frame.size());
+
+        match self.show_summary {
+            true => car::draw_summary(frame, chunks[0], &self.cars, self.current_car),
+            false => self.car().draw(frame, chunks[0])
         }
+
+        frame.render_widget(Paragraph::new(self.status_line()), chunks[1]);
     }
 
-    fn init_vector_statics(&mut self) {
-        for block in self.blocks.iter_mut() {
-            block.init_statics(&self.statics);
+    fn status_line(&self) -> String {
+        if let Some(prompt) = &self.prompt {
+            return match prompt.kind {
+                PromptKind::Jump => format!("Jump to (h:mm:ss): {}", prompt.input),
+                PromptKind::Message => format!("Message to driver: {}", prompt.input)
+            };
         }
-    }
 
-    fn wait_for_initial_message(&self) {
-        let mut buffer = [0; BUFFER_SIZE];
-        println!("Waiting for connection...");
-        self.network.socket.recv(&mut buffer).unwrap();
-        println!("Connection successful!");
-        // TODO we may have to update heartbeat, we may not
+        match self.cars.len() {
+            1 => self.car().status(),
+            count => format!("Car {}/{} {}", self.current_car + 1, count, self.car().status())
+        }
     }
 
     fn is_event_available() -> bool {
-        event::poll(std::time::Duration::from_millis(0)).unwrap()
+        event::poll(Duration::from_millis(0)).unwrap()
     }
 }
 
 fn main() {
-    let server_ip_addr = match get_ip_from_args() {
-        Some(val) => val,
+    let thread_panic = install_shutdown_handlers();
+
+    let layout = config::load_layout();
+    let record = has_flag_in_args(RECORD_FLAG);
+
+    let sources: Vec<TelemetrySource> = match get_flag_value_from_args(REPLAY_FLAG) {
+        Some(path) => match ReplaySource::open(std::path::Path::new(&path)) {
+            Ok(replay) => vec![TelemetrySource::Replay(Box::new(replay))],
+            Err(e) => {
+                println!("Failed to open replay: {}. Exiting...", e);
+                std::process::exit(1);
+            }
+        },
+        None if has_flag_in_args(SIMULATE_FLAG) => {
+            vec![TelemetrySource::Live(NetworkReceiver::spawn_generator(Generator::new(config::load_simulation())))]
+        },
         None => {
-            println!("Failed to supply server IP as argument. Exiting...");
-            std::process::exit(1);
+            let mut server_ip_addrs = get_ips_from_args();
+            if server_ip_addrs.is_empty() {
+                server_ip_addrs = discover_servers();
+            }
+
+            let listen_addr = get_flag_value_from_args(LISTEN_FLAG)
+                .or_else(config::load_listen_address)
+                .map(|addr| parse_listen_address(&addr));
+            let relay_addr = get_flag_value_from_args(RELAY_FLAG);
+            let hello = Hello::new(config::load_encoding(), config::load_max_packet_size())
+                .with_rate(config::load_requested_rate())
+                .with_field_groups(config::load_field_groups());
+            let auth = config::load_shared_key().map(|key| Authenticator::new(&key));
+
+            // Every address is checked before any socket is opened, so a typo
+            // in the last one doesn't leave the others half started
+            let servers: Vec<(String, SocketAddr)> = server_ip_addrs.into_iter()
+                .map(|server_ip_addr| {
+                    let server_addr = resolve_server_address(&server_ip_addr);
+                    (server_ip_addr, server_addr)
+                })
+                .collect();
+
+            servers.into_iter().enumerate()
+                .map(|(index, (server_ip_addr, server_addr))| {
+                    let listen_addr = listen_address_for(listen_addr, server_addr, index);
+                    let network = NetworkInfo::new(listen_addr, server_ip_addr, server_addr, RECV_TIMEOUT, hello.clone());
+                    let network = match network {
+                        Ok(val) => val.with_authenticator(auth.clone()),
+                        Err(e) => {
+                            println!("Failed to listen on {}: {}. Another engineer on this machine may be using it, \
+                                      try {} with another port, or port 0 for any free one. Exiting...",
+                                     listen_addr, e, LISTEN_FLAG);
+                            std::process::exit(1);
+                        }
+                    };
+                    let relay = relay_addr.as_ref()
+                        .map(|addr| bind_relay(addr, index).with_authenticator(auth.clone()));
+                    TelemetrySource::Live(NetworkReceiver::spawn(network, relay))
+                })
+                .collect()
         }
     };
 
-    let mut telemetry_parser = TelemetryParser::new(String::from(LISTEN_IP_ADDR_PORT), server_ip_addr);
+    let car_count = sources.len();
+    let mut cars: Vec<Car> = Vec::new();
 
-    telemetry_parser.preconnect_setup();
+    for source in sources {
+        // Every car gets its own dashboard so no block mixes up their data
+        let dashboard = match Dashboard::from_config(&layout) {
+            Ok(val) => val,
+            Err(e) => {
+                println!("{}. Exiting...", e);
+                std::process::exit(1);
+            }
+        };
+
+        let recorder = match record {
+            true => Some(SessionRecorder::new(&config::load_recording_directory())),
+            false => None
+        };
+
+        let recorder = match car_count {
+            1 => recorder,
+            _ => recorder.map(|recorder| recorder.with_label(source.name()))
+        };
+
+        cars.push(Car::new(source, dashboard, recorder));
+    }
+
+    let mut telemetry_parser = TelemetryParser::new(cars, thread_panic);
 
     terminal_setup();
 
     telemetry_parser.main();
 }
 
-fn get_ip_from_args() -> Option<String> {
-    let args: Vec<String> = std::env::args().collect();
+// Without a server on the command line the engineer picks from the ones
+// that answer on the local network
+fn discover_servers() -> Vec<String> {
+    let discovery = match Discovery::bind(&config::load_discovery_targets()) {
+        Ok(val) => val.with_authenticator(config::load_shared_key().map(|key| Authenticator::new(&key))),
+        Err(e) => {
+            println!("No server IP supplied, and failed to search for one: {}. Exiting...", e);
+            std::process::exit(1);
+        }
+    };
 
-    if args.len() < 2 {
-        return None;
+    terminal_setup();
+    let chosen = picker::pick_servers(discovery, &SHUTDOWN_REQUESTED);
+    terminal_cleanup();
+
+    if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+        std::process::exit(INTERRUPTED_EXIT_CODE);
     }
-    else {
-        return Some(String::from(&args[1]));
+    if chosen.is_empty() {
+        println!("No server chosen. Exiting...");
+        std::process::exit(1);
     }
+    chosen
 }
 
-fn terminal_setup() {
-    crossterm::terminal::enable_raw_mode().unwrap();
-    crossterm::execute!(std::io::stdout(), crossterm::terminal::EnterAlternateScreen).unwrap();
+// Server addresses may be IPv4, IPv6 in brackets, or a host name, and must
+// have a port
+fn resolve_server_address(server_ip_addr: &str) -> SocketAddr {
+    let resolved = match server_ip_addr.to_socket_addrs() {
+        Ok(mut val) => val.next(),
+        Err(e) => {
+            println!("Server address \"{}\" should be of the form <IP Address : Port>, \
+                      with IPv6 addresses in brackets: {}. Exiting...", server_ip_addr, e);
+            std::process::exit(1);
+        }
+    };
+
+    match resolved {
+        Some(val) => val,
+        None => {
+            println!("Server address \"{}\" did not resolve to an IP address. Exiting...", server_ip_addr);
+            std::process::exit(1);
+        }
+    }
 }
 
-fn terminal_cleanup() {
-    crossterm::execute!(std::io::stdout(), crossterm::terminal::LeaveAlternateScreen).unwrap();
-    crossterm::terminal::disable_raw_mode().unwrap();
+fn parse_listen_address(addr: &str) -> SocketAddr {
+    match addr.parse::<SocketAddr>() {
+        Ok(val) => val,
+        Err(_) => {
+            println!("Listen address \"{}\" should be of the form <IP Address : Port>, \
+                      such as 0.0.0.0:9001 or [::]:9001. Exiting...", addr);
+            std::process::exit(1);
+        }
+    }
 }
 
-fn exit_terminal() {
-    terminal_cleanup();
-    std::process::exit(0);
+// Without a listen address every interface is listened on, over IPv4 or
+// IPv6 to match the server. With several cars, each is listened for on the
+// port after the one before, except that port 0 gets each a free port.
+fn listen_address_for(listen_addr: Option<SocketAddr>, server_addr: SocketAddr, index: usize) -> SocketAddr {
+    let mut listen_addr = match listen_addr {
+        Some(val) => val,
+        None => {
+            let any_ip = match server_addr {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            };
+            SocketAddr::new(any_ip, LISTEN_BASE_PORT)
+        }
+    };
+
+    if listen_addr.is_ipv4() != server_addr.is_ipv4() {
+        println!("Listen address {} can't reach server {}, both must be IPv4 or both IPv6. Exiting...",
+                 listen_addr, server_addr);
+        std::process::exit(1);
+    }
+
+    if listen_addr.port() != 0 {
+        match listen_addr.port().checked_add(index as u16) {
+            Some(port) => listen_addr.set_port(port),
+            None => {
+                println!("Not enough ports above {} to listen for every car. Exiting...", listen_addr);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    listen_addr
+}
+
+// With several cars each gets its own relay, on the ports after the given one
+fn bind_relay(addr: &str, index: usize) -> Relay {
+    let relay_addr = match addr.parse::<std::net::SocketAddr>() {
+        Ok(mut socket_addr) => {
+            match socket_addr.port().checked_add(index as u16) {
+                Some(port) => socket_addr.set_port(port),
+                None => {
+                    println!("Not enough ports above {} to relay every car. Exiting...", socket_addr);
+                    std::process::exit(1);
+                }
+            }
+            socket_addr.to_string()
+        },
+        Err(_) => {
+            println!("Relay address \"{}\" should be of the form <IP Address : Port>. Exiting...", addr);
+            std::process::exit(1);
+        }
+    };
+
+    match Relay::bind(&relay_addr) {
+        Ok(val) => val,
+        Err(e) => {
+            println!("Failed to start relay on {}: {}. Exiting...", relay_addr, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Server IPs are the arguments that aren't flags or a flag's value
+fn get_ips_from_args() -> Vec<String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    args.iter().enumerate()
+        .filter(|(i, arg)| {
+            let is_flag_value = *i > 0 && VALUE_FLAGS.contains(&args[i - 1].as_str());
+            !arg.starts_with("--") && !is_flag_value
+        })
+        .map(|(_, arg)| String::from(arg))
+        .collect()
+}
+
+fn get_flag_value_from_args(flag: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    let position = args.iter().position(|arg| arg == flag)?;
+
+    args.get(position + 1).cloned()
 }
 
-fn _switch_blocks_view() {
-    return
+fn has_flag_in_args(flag: &str) -> bool {
+    std::env::args().skip(1).any(|arg| arg == flag)
 }
 
-fn sleep_for(time: u64) {
-    std::thread::sleep(std::time::Duration::from_millis(time));
+// SIGINT and SIGTERM ask the main loop to shut down. A panic on the main
+// thread puts the terminal back before the message is printed, so it can be
+// read, and the cars say goodbye as they are dropped while unwinding. A
+// panic on any other thread asks the main loop to shut down as well, which
+// prints the message on its way out, so it is handed back for the main loop.
+fn install_shutdown_handlers() -> Arc<Mutex<Option<String>>> {
+    if let Err(e) = ctrlc::set_handler(|| SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst)) {
+        println!("Failed to install signal handler: {}. Exiting...", e);
+        std::process::exit(1);
+    }
+
+    let thread_panic = Arc::new(Mutex::new(None));
+    let hook_panic = Arc::clone(&thread_panic);
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if std::thread::current().name() != Some("main") {
+            let thread = std::thread::current().name().unwrap_or("unnamed").to_string();
+            if let Ok(mut panic) = hook_panic.lock() {
+                panic.get_or_insert(format!("thread '{}' {}", thread, info));
+            }
+            SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+            return;
+        }
+
+        terminal_cleanup();
+        default_hook(info);
+    }));
+
+    thread_panic
 }
 
-fn sleep_for_polling_rate() {
-    sleep_for(POLLING_RATE_IN_MS);
+fn is_interrupt(event: &event::Event) -> bool {
+    match event {
+        event::Event::Key(key) => {
+            key.code == event::KeyCode::Char('c') && key.modifiers.contains(event::KeyModifiers::CONTROL)
+        },
+        _ => false
+    }
 }
 
+fn terminal_setup() {
+    crossterm::terminal::enable_raw_mode().unwrap();
+    crossterm::execute!(std::io::stdout(), crossterm::terminal::EnterAlternateScreen).unwrap();
+}
+
+// Called on the way out, including from the panic hook, so failing here
+// must not panic again
+fn terminal_cleanup() {
+    let _ = crossterm::execute!(std::io::stdout(), crossterm::terminal::LeaveAlternateScreen);
+    let _ = crossterm::terminal::disable_raw_mode();
+}
+
+
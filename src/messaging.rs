@@ -0,0 +1,210 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Messaging
+//
+// Short messages from the engineer for ACCRT to show or read out to the
+// driver. Each is sent as "MSG <id> <text>" and repeated until the server
+// answers "ACK <id>", or given up on after a few tries. Servers that don't
+// know about messages never answer, so every message shows as undelivered.
+//
+
+use std::fmt;
+use std::time::{ Duration, Instant };
+
+pub const MESSAGE_PREFIX: &str = "MSG";
+pub const ACK_PREFIX: &[u8] = b"ACK ";
+
+// Long enough for a call, short enough to read at 250 km/h
+pub const MAX_MESSAGE_LENGTH: usize = 64;
+
+const RESEND_INTERVAL: Duration = Duration::from_millis(500);
+const MAX_ATTEMPTS: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Sending,
+    Delivered,
+    Failed
+}
+
+impl fmt::Display for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Delivery::Sending   => "sending",
+            Delivery::Delivered => "delivered",
+            Delivery::Failed    => "NOT DELIVERED"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SentMessage {
+    pub id: u32,
+    pub text: String,
+    pub delivery: Delivery
+}
+
+struct PendingMessage {
+    id: u32,
+    text: String,
+    last_sent: Option<Instant>,
+    attempts: u32
+}
+
+impl PendingMessage {
+    fn send_due(&self) -> bool {
+        match self.last_sent {
+            Some(sent) => sent.elapsed() >= RESEND_INTERVAL,
+            None => true
+        }
+    }
+}
+
+/// Messages waiting to be acknowledged
+pub struct Outbox {
+    pending: Vec<PendingMessage>,
+    updates: Vec<(u32, Delivery)>
+}
+
+impl Outbox {
+    pub fn new() -> Outbox {
+        Outbox {
+            pending: Vec::new(),
+            updates: Vec::new()
+        }
+    }
+
+    pub fn queue(&mut self, id: u32, text: String) {
+        self.pending.push(PendingMessage {
+            id,
+            text,
+            last_sent: None,
+            attempts: 0
+        });
+    }
+
+    /// The datagrams to send now, new messages and ones whose
+    /// acknowledgement is overdue. Messages out of tries are failed.
+    pub fn due(&mut self) -> Vec<String> {
+        let updates = &mut self.updates;
+        self.pending.retain(|message| {
+            let out_of_tries = message.attempts >= MAX_ATTEMPTS && message.send_due();
+            if out_of_tries {
+                updates.push((message.id, Delivery::Failed));
+            }
+            !out_of_tries
+        });
+
+        self.pending.iter_mut()
+            .filter(|message| message.send_due())
+            .map(|message| {
+                message.last_sent = Some(Instant::now());
+                message.attempts += 1;
+                format!("{} {} {}", MESSAGE_PREFIX, message.id, message.text)
+            })
+            .collect()
+    }
+
+    /// Takes an "ACK <id>" datagram, ignoring ids that aren't pending as
+    /// those are repeats of an acknowledgement already taken
+    pub fn on_ack(&mut self, datagram: &[u8]) {
+        let id = std::str::from_utf8(&datagram[ACK_PREFIX.len()..]).ok()
+            .and_then(|id| id.trim().parse::<u32>().ok());
+
+        if let Some(index) = self.pending.iter().position(|message| Some(message.id) == id) {
+            let message = self.pending.remove(index);
+            self.updates.push((message.id, Delivery::Delivered));
+        }
+    }
+
+    /// Deliveries settled since the last call
+    pub fn take_updates(&mut self) -> Vec<(u32, Delivery)> {
+        std::mem::take(&mut self.updates)
+    }
+}
+
+impl Default for Outbox {
+    fn default() -> Outbox {
+        Outbox::new()
+    }
+}
+
+/// Makes typed text fit to send: one line, and no longer than the limit
+pub fn clean_message(text: &str) -> Option<String> {
+    let text: String = text.split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .chars()
+        .take(MAX_MESSAGE_LENGTH)
+        .collect();
+
+    match text.is_empty() {
+        true => None,
+        false => Some(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As if every message was last sent a resend interval ago
+    fn make_overdue(outbox: &mut Outbox) {
+        for message in outbox.pending.iter_mut() {
+            message.last_sent = message.last_sent.and_then(|sent| sent.checked_sub(RESEND_INTERVAL));
+        }
+    }
+
+    #[test]
+    fn resends_until_acknowledged() {
+        let mut outbox = Outbox::new();
+        outbox.queue(1, String::from("BOX THIS LAP"));
+        assert_eq!(outbox.due(), vec!["MSG 1 BOX THIS LAP"]);
+
+        // Nothing is repeated before the interval is up
+        assert!(outbox.due().is_empty());
+        make_overdue(&mut outbox);
+        assert_eq!(outbox.due(), vec!["MSG 1 BOX THIS LAP"]);
+
+        outbox.on_ack(b"ACK 1");
+        assert_eq!(outbox.take_updates(), vec![(1, Delivery::Delivered)]);
+        make_overdue(&mut outbox);
+        assert!(outbox.due().is_empty());
+
+        // Repeated acknowledgements and unknown ids change nothing
+        outbox.on_ack(b"ACK 1");
+        outbox.on_ack(b"ACK 2");
+        outbox.on_ack(b"ACK x");
+        assert!(outbox.take_updates().is_empty());
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut outbox = Outbox::new();
+        outbox.queue(1, String::from("PUSH"));
+        outbox.queue(2, String::from("SAVE FUEL"));
+        outbox.on_ack(b"ACK 2\n");
+        assert_eq!(outbox.take_updates(), vec![(2, Delivery::Delivered)]);
+
+        for _ in 0..MAX_ATTEMPTS {
+            assert_eq!(outbox.due(), vec!["MSG 1 PUSH"]);
+            assert!(outbox.take_updates().is_empty());
+            make_overdue(&mut outbox);
+        }
+
+        assert!(outbox.due().is_empty());
+        assert_eq!(outbox.take_updates(), vec![(1, Delivery::Failed)]);
+
+        // A late acknowledgement doesn't undo the failure
+        outbox.on_ack(b"ACK 1");
+        assert!(outbox.take_updates().is_empty());
+    }
+
+    #[test]
+    fn cleans_messages() {
+        assert_eq!(clean_message("  BOX \n THIS\tLAP "), Some(String::from("BOX THIS LAP")));
+        assert_eq!(clean_message(" \n "), None);
+        assert_eq!(clean_message(&"X".repeat(100)).map(|text| text.len()), Some(MAX_MESSAGE_LENGTH));
+    }
+}
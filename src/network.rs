@@ -0,0 +1,759 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Network
+//
+
+use std::collections::{ BTreeMap, VecDeque };
+use std::fmt;
+use std::io;
+use std::net::{ SocketAddr, UdpSocket };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use crate::auth::Authenticator;
+use crate::messaging::{ Delivery, Outbox, ACK_PREFIX };
+use crate::protocol::{ Hello, Negotiation, Welcome, WELCOME_PREFIX };
+use crate::reassembly::{ self, Reassembler, CHUNK_PREFIX };
+use crate::relay::Relay;
+use crate::telemetry::Encoding;
+
+pub const HANDSHAKE_MESSAGE: &str = "Give me the data!";
+pub const HEARTBEAT_MESSAGE: &str = "I'm alive!";
+// Sent on the way out, so the server can stop sending straight away rather
+// than waiting for heartbeats to stop
+pub const GOODBYE_MESSAGE: &str = "GOODBYE";
+
+// Sent after each heartbeat as "PING <seq> <our unix ms>". A server that
+// understands it answers "PONG <seq> <our unix ms> <its unix ms>", older
+// servers simply ignore it.
+pub const PING_MESSAGE: &str = "PING";
+pub const PONG_MESSAGE: &str = "PONG";
+const PONG_PREFIX: &[u8] = b"PONG ";
+const MAX_PENDING_PINGS: usize = 8;
+
+// Sent after each original handshake as "ENCODING <name>" when a binary
+// encoding is wanted, for servers that predate the hello. Servers that don't
+// understand it go on sending JSON.
+pub const ENCODING_MESSAGE: &str = "ENCODING";
+
+const HEARTBEAT_DELTA: Duration = Duration::from_millis(2000);
+// Whoever we send heartbeats to can take a few missed ones as us being gone
+pub const CLIENT_TIMEOUT: Duration = Duration::from_millis(10000);
+
+// How long the server may stay silent before we consider the data stale,
+// and how much longer before we give up on it and handshake again
+const STALE_TIMEOUT: Duration = Duration::from_millis(1000);
+const RECONNECT_TIMEOUT: Duration = Duration::from_millis(5000);
+
+const HANDSHAKE_BACKOFF_MIN: Duration = Duration::from_millis(500);
+const HANDSHAKE_BACKOFF_MAX: Duration = Duration::from_millis(8000);
+
+// Packet ids remembered for telling a late packet from a duplicate
+const RECENT_PACKET_IDS: usize = 64;
+// A jump back further than this is ACC restarting its count, not a late packet
+const PACKET_ID_RESET_THRESHOLD: u64 = 1000;
+const PACKET_RATE_WINDOW: Duration = Duration::from_millis(1000);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Disconnected,
+    Handshaking,
+    Streaming,
+    Stale,
+    Reconnecting
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ConnectionState::Disconnected => "Disconnected",
+            ConnectionState::Handshaking  => "Handshaking",
+            ConnectionState::Streaming    => "Streaming",
+            ConnectionState::Stale        => "Stale",
+            ConnectionState::Reconnecting => "Reconnecting"
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// How well packets are making it across the link. Counts are since the
+/// program started, the rate and jitter are recent.
+#[derive(Debug, Clone)]
+pub struct LinkStats {
+    pub received: u64,
+    pub lost: u64,
+    pub out_of_order: u64,
+    pub duplicates: u64,
+    pub jitter_ms: f64,
+    pub packets_per_second: f64,
+    pub bytes_per_second: BTreeMap<Encoding, f64>,
+    pub rtt_ms: Option<f64>,
+    pub clock_offset_ms: Option<f64>,
+    pub parse_failures: u64,
+    pub truncated: u64,
+    pub incomplete: u64,
+    pub unauthenticated: u64,
+    highest_id: Option<u64>,
+    recent_ids: VecDeque<u64>,
+    last_arrival: Option<Instant>,
+    last_interval_ms: Option<f64>,
+    window_start: Instant,
+    window_packets: u32,
+    window_bytes: BTreeMap<Encoding, u64>
+}
+
+impl LinkStats {
+    pub fn new() -> LinkStats {
+        LinkStats {
+            received: 0,
+            lost: 0,
+            out_of_order: 0,
+            duplicates: 0,
+            jitter_ms: 0.0,
+            packets_per_second: 0.0,
+            bytes_per_second: BTreeMap::new(),
+            rtt_ms: None,
+            clock_offset_ms: None,
+            parse_failures: 0,
+            truncated: 0,
+            incomplete: 0,
+            unauthenticated: 0,
+            highest_id: None,
+            recent_ids: VecDeque::new(),
+            last_arrival: None,
+            last_interval_ms: None,
+            window_start: Instant::now(),
+            window_packets: 0,
+            window_bytes: BTreeMap::new()
+        }
+    }
+
+    /// Share of the packets sent that never arrived, as a percentage
+    pub fn loss_percent(&self) -> f64 {
+        match self.received + self.lost {
+            0 => 0.0,
+            total => self.lost as f64 * 100.0 / total as f64
+        }
+    }
+
+    /// Messages that arrived but couldn't be used
+    pub fn dropped(&self) -> u64 {
+        self.parse_failures + self.truncated + self.incomplete + self.unauthenticated
+    }
+
+    fn on_arrival(&mut self, now: Instant, encoding: Encoding, bytes: usize) {
+        // Jitter is smoothed the same way as RTP (RFC 3550), but from the
+        // change in time between arrivals as packets carry no send time
+        if let Some(last) = self.last_arrival {
+            let interval_ms = now.duration_since(last).as_secs_f64() * 1000.0;
+            if let Some(last_interval_ms) = self.last_interval_ms {
+                let delta = (interval_ms - last_interval_ms).abs();
+                self.jitter_ms += (delta - self.jitter_ms) / 16.0;
+            }
+            self.last_interval_ms = Some(interval_ms);
+        }
+        self.last_arrival = Some(now);
+
+        self.window_packets += 1;
+        *self.window_bytes.entry(encoding).or_insert(0) += bytes as u64;
+
+        let window = now.duration_since(self.window_start);
+        if window >= PACKET_RATE_WINDOW {
+            self.packets_per_second = self.window_packets as f64 / window.as_secs_f64();
+            // Only the encodings seen in the last window, so a server that
+            // switched doesn't leave its old rate showing forever
+            self.bytes_per_second = self.window_bytes.iter()
+                .map(|(encoding, bytes)| (*encoding, *bytes as f64 / window.as_secs_f64()))
+                .collect();
+            self.window_start = now;
+            self.window_packets = 0;
+            self.window_bytes.clear();
+        }
+    }
+
+    // The server's clock is assumed to have been read halfway through the
+    // round trip, as in NTP. Both are smoothed like TCP's round trip time.
+    fn on_pong(&mut self, rtt_ms: f64, sent_unix_ms: u64, server_unix_ms: u64) {
+        let offset_ms = server_unix_ms as f64 - (sent_unix_ms as f64 + rtt_ms / 2.0);
+
+        self.rtt_ms = Some(match self.rtt_ms {
+            Some(smoothed) => smoothed + (rtt_ms - smoothed) / 8.0,
+            None => rtt_ms
+        });
+        self.clock_offset_ms = Some(match self.clock_offset_ms {
+            Some(smoothed) => smoothed + (offset_ms - smoothed) / 8.0,
+            None => offset_ms
+        });
+    }
+
+    fn on_packet_id(&mut self, packet_id: u64) {
+        let highest = match self.highest_id {
+            Some(val) => val,
+            None => {
+                self.accept_packet_id(packet_id);
+                return;
+            }
+        };
+
+        if packet_id > highest {
+            self.lost += packet_id - highest - 1;
+            self.accept_packet_id(packet_id);
+        }
+        else if self.recent_ids.contains(&packet_id) {
+            self.duplicates += 1;
+        }
+        else if highest - packet_id > PACKET_ID_RESET_THRESHOLD {
+            self.recent_ids.clear();
+            self.accept_packet_id(packet_id);
+        }
+        else {
+            // It was counted as lost when the packets after it arrived first
+            self.out_of_order += 1;
+            self.lost = self.lost.saturating_sub(1);
+            self.received += 1;
+            self.remember_packet_id(packet_id);
+        }
+    }
+
+    fn accept_packet_id(&mut self, packet_id: u64) {
+        self.highest_id = Some(packet_id);
+        self.received += 1;
+        self.remember_packet_id(packet_id);
+    }
+
+    fn remember_packet_id(&mut self, packet_id: u64) {
+        self.recent_ids.push_back(packet_id);
+        if self.recent_ids.len() > RECENT_PACKET_IDS {
+            self.recent_ids.pop_front();
+        }
+    }
+}
+
+impl Default for LinkStats {
+    fn default() -> LinkStats {
+        LinkStats::new()
+    }
+}
+
+pub struct NetworkInfo {
+    socket:        UdpSocket,
+    buffer:        Vec<u8>,
+    reassembler:   Reassembler,
+    pub server_ip: String,
+    server_addr:   SocketAddr,
+    _listen_addr:  SocketAddr,
+    state:         ConnectionState,
+    heartbeat:     Instant,
+    last_packet:   Instant,
+    last_handshake: Option<Instant>,
+    backoff:       Duration,
+    link:          LinkStats,
+    ping_seq:      u32,
+    pending_pings: VecDeque<(u32, Instant)>,
+    hello:         Hello,
+    negotiation:   Negotiation,
+    handshake_attempts: u32,
+    outbox:        Outbox,
+    auth:          Option<Authenticator>,
+    message_bytes: usize // As they came off the wire, since the last whole message
+}
+
+impl NetworkInfo {
+    /// Listens on the given address, where port 0 lets the system pick a
+    /// free one, for the server at `server_addr`. `server_ip` is what the
+    /// engineer typed, kept for display. Datagrams over the hello's
+    /// `max_packet_size` bytes are counted as truncated.
+    pub fn new(listen_addr: SocketAddr, server_ip: String, server_addr: SocketAddr, recv_timeout: Duration,
+               hello: Hello) -> io::Result<NetworkInfo> {
+        let socket = UdpSocket::bind(listen_addr)?;
+        socket.set_read_timeout(Some(recv_timeout))?;
+        let listen_addr = socket.local_addr()?;
+
+        Ok(NetworkInfo {
+            socket,
+            // The socket silently cuts off whatever doesn't fit, so one spare
+            // byte is the only way to tell a datagram was too big
+            buffer: vec![0; hello.max_packet_size + 1],
+            reassembler: Reassembler::new(),
+            server_ip,
+            server_addr,
+            _listen_addr: listen_addr,
+            state: ConnectionState::Disconnected,
+            heartbeat: Instant::now(),
+            last_packet: Instant::now(),
+            last_handshake: None,
+            backoff: HANDSHAKE_BACKOFF_MIN,
+            link: LinkStats::new(),
+            ping_seq: 0,
+            pending_pings: VecDeque::new(),
+            hello,
+            negotiation: Negotiation::Pending,
+            handshake_attempts: 0,
+            outbox: Outbox::new(),
+            auth: None,
+            message_bytes: 0
+        })
+    }
+
+    /// With a shared key everything sent is signed, and anything received
+    /// that isn't signed with the same key is counted and dropped
+    pub fn with_authenticator(mut self, auth: Option<Authenticator>) -> NetworkInfo {
+        self.auth = auth;
+        self
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    pub fn link_stats(&self) -> &LinkStats {
+        &self.link
+    }
+
+    pub fn negotiation(&self) -> &Negotiation {
+        &self.negotiation
+    }
+
+    /// Feeds the id of a packet that parsed into the loss and ordering
+    /// counts. Packets outside a session all carry id 0 and are ignored.
+    pub fn record_packet_id(&mut self, packet_id: u64) {
+        if packet_id != 0 {
+            self.link.on_packet_id(packet_id);
+        }
+    }
+
+    pub fn send_goodbye(&mut self) {
+        let _ = self.send(GOODBYE_MESSAGE.as_bytes());
+        self.state = ConnectionState::Disconnected;
+    }
+
+    /// Queues a message for the driver, sent on the next call to
+    /// `send_messages`
+    pub fn queue_message(&mut self, id: u32, text: String) {
+        self.outbox.queue(id, text);
+    }
+
+    /// Sends new messages and repeats unacknowledged ones
+    pub fn send_messages(&mut self) {
+        for datagram in self.outbox.due() {
+            let _ = self.send(datagram.as_bytes());
+        }
+    }
+
+    /// Messages delivered or given up on since the last call
+    pub fn take_deliveries(&mut self) -> Vec<(u32, Delivery)> {
+        self.outbox.take_updates()
+    }
+
+    /// A message that arrived but didn't parse
+    pub fn record_parse_failure(&mut self) {
+        self.link.parse_failures += 1;
+    }
+
+    /// Waits up to the receive timeout for a datagram, advancing the
+    /// connection state either way. Returns the message that arrived, or
+    /// None if nothing did, if it was an answer to a ping rather than
+    /// telemetry, if it was only part of a message, or if it wasn't signed
+    /// with the shared key. With a relay, telemetry is passed on datagram by
+    /// datagram as it came, so its clients get the same chunks we did.
+    pub fn recv(&mut self, relay: Option<&mut Relay>) -> Option<Vec<u8>> {
+        self.link.incomplete += self.reassembler.expire();
+
+        // On every call, whatever arrives, so that a flood of datagrams
+        // that are dropped can't hold up the handshake or heartbeats
+        self.tick();
+
+        let buf_size = match self.socket.recv(&mut self.buffer) {
+            Ok(val) => val,
+            Err(e) if is_timeout(&e) => { return None; },
+            Err(_e) => {
+                // Typically the server's port being closed (ICMP unreachable),
+                // which the handshake backoff will keep retrying
+                if self.state == ConnectionState::Streaming || self.state == ConnectionState::Stale {
+                    self.state = ConnectionState::Reconnecting;
+                }
+                return None;
+            }
+        };
+
+        if buf_size == self.buffer.len() {
+            // A signature can't be checked on what was cut off, but without
+            // a key it is still the server sending
+            if self.auth.is_none() {
+                self.on_packet_received();
+            }
+            self.link.truncated += 1;
+            return None;
+        }
+
+        let datagram_size = buf_size;
+        let buf_size = self.authenticate(buf_size)?;
+        let datagram = &self.buffer[..buf_size];
+
+        if datagram.starts_with(PONG_PREFIX) {
+            let message = datagram.to_vec();
+            self.on_pong(&message);
+            None
+        }
+        else if datagram.starts_with(ACK_PREFIX) {
+            self.outbox.on_ack(datagram);
+            None
+        }
+        else if datagram.starts_with(WELCOME_PREFIX) {
+            match Welcome::parse(datagram) {
+                Ok(welcome) => {
+                    // The server is there, even if its data has yet to start
+                    self.on_packet_received();
+                    self.negotiation = Negotiation::Welcomed(Box::new(welcome));
+                },
+                Err(_) => self.link.parse_failures += 1
+            }
+            None
+        }
+        else {
+            if let Some(relay) = relay {
+                relay.forward(datagram);
+            }
+            self.on_packet_received();
+            self.message_bytes += datagram_size;
+            self.take_message(buf_size)
+        }
+    }
+
+    pub fn send_heartbeat(&mut self) {
+        if self.state != ConnectionState::Streaming && self.state != ConnectionState::Stale {
+            return;
+        }
+
+        let current_time = Instant::now();
+
+        if current_time.duration_since(self.heartbeat) > HEARTBEAT_DELTA {
+            if self.send(HEARTBEAT_MESSAGE.as_bytes()).is_err() {
+                self.state = ConnectionState::Disconnected;
+            }
+            self.send_ping();
+            self.heartbeat = current_time;
+        }
+    }
+
+    fn send_ping(&mut self) {
+        self.ping_seq = self.ping_seq.wrapping_add(1);
+        let message = format!("{} {} {}", PING_MESSAGE, self.ping_seq, unix_ms());
+
+        if self.send(message.as_bytes()).is_ok() {
+            self.pending_pings.push_back((self.ping_seq, Instant::now()));
+            if self.pending_pings.len() > MAX_PENDING_PINGS {
+                self.pending_pings.pop_front();
+            }
+        }
+    }
+
+    // Malformed answers and answers to pings we have given up on are dropped
+    fn on_pong(&mut self, message: &[u8]) {
+        let message = String::from_utf8_lossy(message);
+        let fields: Vec<u64> = message.split_whitespace()
+            .skip(1)
+            .filter_map(|field| field.parse().ok())
+            .collect();
+
+        let (seq, sent_unix_ms, server_unix_ms) = match fields[..] {
+            [seq, sent, server] => (seq, sent, server),
+            _ => { return; }
+        };
+
+        let index = match self.pending_pings.iter().position(|(pending, _)| *pending as u64 == seq) {
+            Some(val) => val,
+            None => { return; }
+        };
+
+        let (_, sent) = self.pending_pings.remove(index).unwrap();
+        let rtt_ms = sent.elapsed().as_secs_f64() * 1000.0;
+        self.link.on_pong(rtt_ms, sent_unix_ms, server_unix_ms);
+    }
+
+    // Everything to the server goes through here to be signed
+    fn send(&self, message: &[u8]) -> io::Result<usize> {
+        match &self.auth {
+            Some(auth) => self.socket.send_to(&auth.sign(message), self.server_addr),
+            None => self.socket.send_to(message, self.server_addr)
+        }
+    }
+
+    // With a shared key, moves the contents of a correctly signed datagram
+    // to the front of the buffer and returns their length. Anything else is
+    // counted and dropped before it can be taken for the server.
+    fn authenticate(&mut self, buf_size: usize) -> Option<usize> {
+        let auth = match &self.auth {
+            Some(val) => val,
+            None => { return Some(buf_size); }
+        };
+
+        match auth.verify(&self.buffer[..buf_size]).map(|contents| contents.len()) {
+            Some(length) => {
+                self.buffer.copy_within(buf_size - length..buf_size, 0);
+                Some(length)
+            },
+            None => {
+                self.link.unauthenticated += 1;
+                None
+            }
+        }
+    }
+
+    fn take_message(&mut self, buf_size: usize) -> Option<Vec<u8>> {
+        let datagram = &self.buffer[..buf_size];
+        let message = if datagram.starts_with(CHUNK_PREFIX) {
+            match reassembly::parse_chunk(datagram) {
+                Some(chunk) => self.reassembler.add(chunk)?,
+                None => {
+                    self.link.parse_failures += 1;
+                    return None;
+                }
+            }
+        }
+        else {
+            datagram.to_vec()
+        };
+
+        // Rate and jitter are for whole messages, however many chunks they
+        // took, and the bytes for everything that came with them
+        self.link.on_arrival(Instant::now(), Encoding::detect(&message), self.message_bytes);
+        self.message_bytes = 0;
+        Some(message)
+    }
+
+    fn on_packet_received(&mut self) {
+        // Data without a welcome first comes from a server predating the hello
+        if self.negotiation == Negotiation::Pending {
+            self.negotiation = Negotiation::Legacy;
+        }
+
+        self.last_packet = Instant::now();
+        self.handshake_attempts = 0;
+        self.last_handshake = None;
+        self.backoff = HANDSHAKE_BACKOFF_MIN;
+        self.state = ConnectionState::Streaming;
+    }
+
+    fn tick(&mut self) {
+        let silence = self.last_packet.elapsed();
+
+        match self.state {
+            ConnectionState::Disconnected => {
+                self.state = ConnectionState::Handshaking;
+                self.send_handshake();
+            },
+            ConnectionState::Streaming => {
+                if silence > STALE_TIMEOUT {
+                    self.state = ConnectionState::Stale;
+                }
+            },
+            ConnectionState::Stale => {
+                if silence > RECONNECT_TIMEOUT {
+                    self.state = ConnectionState::Reconnecting;
+                    self.send_handshake();
+                }
+            },
+            ConnectionState::Handshaking | ConnectionState::Reconnecting => {
+                let backoff_elapsed = match self.last_handshake {
+                    Some(sent) => sent.elapsed() > self.backoff,
+                    None => true
+                };
+
+                if backoff_elapsed {
+                    self.backoff = std::cmp::min(self.backoff * 2, HANDSHAKE_BACKOFF_MAX);
+                    self.send_handshake();
+                }
+            }
+        }
+    }
+
+    // Hellos and the original handshake take turns, so an older server is
+    // picked up one backoff later than a newer one
+    fn send_handshake(&mut self) {
+        // Whoever answers this time may not be the server that answered before
+        if self.handshake_attempts == 0 {
+            self.negotiation = Negotiation::Pending;
+        }
+
+        // A failed send is retried on the next backoff like an unanswered one
+        if self.handshake_attempts & 1 == 0 {
+            let _ = self.send(self.hello.to_message().as_bytes());
+        }
+        else {
+            let _ = self.send(HANDSHAKE_MESSAGE.as_bytes());
+
+            let encoding = self.hello.preferred_encoding();
+            if encoding != Encoding::Json {
+                let request = format!("{} {}", ENCODING_MESSAGE, encoding);
+                let _ = self.send(request.as_bytes());
+            }
+        }
+
+        self.handshake_attempts += 1;
+        self.last_handshake = Some(Instant::now());
+    }
+}
+
+pub fn unix_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::{ PhysicsData, TelemetryPacket };
+
+    fn stats_after(packet_ids: &[u64]) -> LinkStats {
+        let mut stats = LinkStats::new();
+        for packet_id in packet_ids {
+            stats.on_packet_id(*packet_id);
+        }
+        stats
+    }
+
+    #[test]
+    fn counts_in_order_packets() {
+        let stats = stats_after(&[5, 6, 7, 8]);
+        assert_eq!(stats.received, 4);
+        assert_eq!(stats.lost, 0);
+        assert_eq!(stats.out_of_order, 0);
+        assert_eq!(stats.duplicates, 0);
+    }
+
+    #[test]
+    fn counts_gaps_as_lost() {
+        let stats = stats_after(&[1, 2, 5, 6, 10]);
+        assert_eq!(stats.received, 5);
+        assert_eq!(stats.lost, 5);
+    }
+
+    #[test]
+    fn late_packets_are_no_longer_lost() {
+        let stats = stats_after(&[1, 3, 4, 2, 5]);
+        assert_eq!(stats.received, 5);
+        assert_eq!(stats.lost, 0);
+        assert_eq!(stats.out_of_order, 1);
+        assert_eq!(stats.duplicates, 0);
+    }
+
+    #[test]
+    fn counts_duplicates_once() {
+        let stats = stats_after(&[1, 2, 2, 3, 1]);
+        assert_eq!(stats.received, 3);
+        assert_eq!(stats.duplicates, 2);
+        assert_eq!(stats.out_of_order, 0);
+
+        // A late packet repeated is a duplicate the second time
+        let stats = stats_after(&[1, 3, 2, 2]);
+        assert_eq!(stats.out_of_order, 1);
+        assert_eq!(stats.duplicates, 1);
+    }
+
+    #[test]
+    fn follows_the_sender_restarting() {
+        let stats = stats_after(&[5000, 5001, 1, 2, 3]);
+        assert_eq!(stats.received, 5);
+        assert_eq!(stats.lost, 0);
+        assert_eq!(stats.out_of_order, 0);
+        assert_eq!(stats.highest_id, Some(3));
+
+        // Within the threshold it is a very late packet instead
+        let stats = stats_after(&[1500, 1501, 600]);
+        assert_eq!(stats.out_of_order, 1);
+        assert_eq!(stats.highest_id, Some(1501));
+    }
+
+    // A server on a local socket, speaking the protocol by hand
+    fn fake_server() -> UdpSocket {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        server
+    }
+
+    fn connect(server: &UdpSocket, auth: Option<Authenticator>) -> NetworkInfo {
+        let hello = Hello::new(Encoding::Json, 4096);
+        NetworkInfo::new("127.0.0.1:0".parse().unwrap(), String::from("test"), server.local_addr().unwrap(),
+                         Duration::from_millis(20), hello)
+            .unwrap()
+            .with_authenticator(auth)
+    }
+
+    // The first call sends the hello, which the server then waits for
+    fn hello_from(network: &mut NetworkInfo, server: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+        assert!(network.recv(None).is_none());
+
+        let mut buffer = [0; 1024];
+        let (size, engineer) = server.recv_from(&mut buffer).unwrap();
+        (buffer[..size].to_vec(), engineer)
+    }
+
+    fn recv_message(network: &mut NetworkInfo) -> Option<Vec<u8>> {
+        (0..20).find_map(|_| network.recv(None))
+    }
+
+    fn welcome() -> Vec<u8> {
+        let mut welcome = WELCOME_PREFIX.to_vec();
+        welcome.extend_from_slice(br#"{"protocol":1,"encoding":"json","rate_hz":60.0}"#);
+        welcome
+    }
+
+    fn packet(packet_id: u64) -> Vec<u8> {
+        let packet = TelemetryPacket {
+            physics_data: PhysicsData { packet_id, ..Default::default() },
+            ..Default::default()
+        };
+        serde_json::to_vec(&packet).unwrap()
+    }
+
+    #[test]
+    fn handshakes_and_receives_chunked_packets() {
+        let server = fake_server();
+        let mut network = connect(&server, None);
+
+        let (hello, engineer) = hello_from(&mut network, &server);
+        assert!(hello.starts_with(b"HELLO {"));
+        assert_eq!(network.state(), ConnectionState::Handshaking);
+
+        server.send_to(&welcome(), engineer).unwrap();
+        assert!(recv_message(&mut network).is_none());
+        assert_eq!(network.state(), ConnectionState::Streaming);
+        assert_eq!(network.negotiation().to_string(), "v1 json 60 Hz");
+
+        // Sent back to front, to be put back together in order
+        let raw = packet(42);
+        for chunk in reassembly::split_into_chunks(&raw, 1, 300).unwrap().iter().rev() {
+            server.send_to(chunk, engineer).unwrap();
+        }
+        let message = recv_message(&mut network).unwrap();
+        assert_eq!(message, raw);
+        assert_eq!(TelemetryPacket::decode(&message).unwrap().physics_data.packet_id, 42);
+    }
+
+    #[test]
+    fn only_takes_datagrams_signed_with_the_key() {
+        let auth = Authenticator::new("shared key");
+        let server = fake_server();
+        let mut network = connect(&server, Some(auth.clone()));
+
+        let (hello, engineer) = hello_from(&mut network, &server);
+        assert!(auth.verify(&hello).unwrap().starts_with(b"HELLO {"));
+
+        // Someone else's data doesn't count as the server answering
+        server.send_to(&packet(7), engineer).unwrap();
+        server.send_to(&Authenticator::new("another key").sign(&packet(7)), engineer).unwrap();
+        assert!(recv_message(&mut network).is_none());
+        assert_eq!(network.link_stats().unauthenticated, 2);
+        assert_eq!(network.state(), ConnectionState::Handshaking);
+
+        server.send_to(&auth.sign(&welcome()), engineer).unwrap();
+        server.send_to(&auth.sign(&packet(8)), engineer).unwrap();
+        let message = recv_message(&mut network).unwrap();
+        assert_eq!(TelemetryPacket::decode(&message).unwrap().physics_data.packet_id, 8);
+        assert_eq!(network.state(), ConnectionState::Streaming);
+    }
+}
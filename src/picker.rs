@@ -0,0 +1,157 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Picker
+//
+// The startup screen shown when no server is given on the command line. It
+// lists the servers found on the local network as they answer, and lets the
+// engineer choose one, or mark several to follow at once.
+//
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::Duration;
+
+use crossterm::event::{ self, Event, KeyCode, KeyModifiers };
+use tui::layout::{ Constraint, Direction, Layout };
+use tui::style::{ Modifier, Style };
+use tui::widgets::{ Block, Borders, Paragraph, Row, Table };
+use tui::{ Frame, Terminal };
+
+use crate::discovery::Discovery;
+use crate::tui_blocks::TermBackend;
+
+// Keys are waited on for this long before the list is refreshed
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Picker {
+    discovery: Discovery,
+    selected: usize,
+    marked: HashSet<SocketAddr>
+}
+
+/// Runs the picker until the engineer connects or gives up. Returns the
+/// addresses chosen, or nothing if the picker was left with Escape or the
+/// program was asked to stop.
+pub fn pick_servers(discovery: Discovery, stop: &AtomicBool) -> Vec<String> {
+    let mut terminal = Terminal::new(TermBackend::new(std::io::stdout())).unwrap();
+    let mut picker = Picker {
+        discovery,
+        selected: 0,
+        marked: HashSet::new()
+    };
+
+    loop {
+        if stop.load(Ordering::SeqCst) {
+            return Vec::new();
+        }
+
+        picker.discovery.poll();
+        picker.selected = picker.selected.min(picker.discovery.servers().len().saturating_sub(1));
+
+        terminal.draw(|frame| picker.draw(frame)).unwrap();
+
+        if !event::poll(POLL_INTERVAL).unwrap() {
+            continue;
+        }
+
+        let key = match event::read().unwrap() {
+            Event::Key(key) => key,
+            _ => continue
+        };
+
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            stop.store(true, Ordering::SeqCst);
+            return Vec::new();
+        }
+
+        match key.code {
+            KeyCode::Up => picker.selected = picker.selected.saturating_sub(1),
+            KeyCode::Down => picker.selected += 1,
+            KeyCode::Char(' ') => picker.toggle_mark(),
+            KeyCode::Enter => {
+                let chosen = picker.chosen();
+                if !chosen.is_empty() {
+                    return chosen;
+                }
+            },
+            KeyCode::Esc | KeyCode::Char('q') => { return Vec::new(); }
+            _ => { }
+        }
+    }
+}
+
+impl Picker {
+    fn toggle_mark(&mut self) {
+        if let Some(server) = self.discovery.servers().get(self.selected) {
+            if !self.marked.remove(&server.address) {
+                self.marked.insert(server.address);
+            }
+        }
+    }
+
+    // The marked servers still around, or the highlighted one if none are
+    fn chosen(&self) -> Vec<String> {
+        let servers = self.discovery.servers();
+        let marked: Vec<String> = servers.iter()
+            .filter(|server| self.marked.contains(&server.address))
+            .map(|server| server.address.to_string())
+            .collect();
+
+        match marked.is_empty() {
+            true => servers.get(self.selected).map(|server| server.address.to_string()).into_iter().collect(),
+            false => marked
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame<TermBackend>) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(0), Constraint::Length(1)].as_ref())
+            .split(frame.size());
+
+        let header = Row::new(vec!["", "Server", "Driver", "Car", "Track"])
+            .style(Style::default().add_modifier(Modifier::BOLD));
+
+        let rows: Vec<Row> = self.discovery.servers().iter().enumerate()
+            .map(|(index, server)| {
+                let mark = match self.marked.contains(&server.address) {
+                    true => "*",
+                    false => ""
+                };
+                let row = Row::new(vec![
+                    String::from(mark),
+                    server.address.to_string(),
+                    server.driver.clone(),
+                    server.car.clone(),
+                    server.track.clone()
+                ]);
+
+                match index == self.selected {
+                    true => row.style(Style::default().add_modifier(Modifier::REVERSED)),
+                    false => row
+                }
+            })
+            .collect();
+
+        let widths = [
+            Constraint::Length(1),
+            Constraint::Length(21),
+            Constraint::Length(20),
+            Constraint::Length(24),
+            Constraint::Length(16)
+        ];
+
+        let table = Table::new(rows)
+            .header(header)
+            .widths(&widths)
+            .block(Block::default().borders(Borders::ALL).title("ACCRT servers on this network"));
+        frame.render_widget(table, chunks[0]);
+
+        let status = match self.discovery.servers().len() {
+            0 => "Searching for servers...  Esc to quit",
+            _ => "Up/Down to choose, Space to mark several, Enter to connect, Esc to quit"
+        };
+        frame.render_widget(Paragraph::new(status), chunks[1]);
+    }
+}
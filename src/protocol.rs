@@ -0,0 +1,126 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Protocol
+//
+// The structured handshake. The engineer says what it is and what it would
+// like in a hello, and a server that understands it answers with a welcome
+// saying what it will actually send:
+//
+//     HELLO {"protocol":1,"client_version":"0.1.0","encodings":["msgpack","json"],...}
+//     WELCOME {"protocol":1,"encoding":"msgpack","rate_hz":60.0,...}
+//
+// Older servers only know the original "Give me the data!", which is sent
+// in between hellos. A server that starts sending data without a welcome is
+// one of those, and is taken to be sending everything as JSON.
+//
+
+use std::fmt;
+
+use serde::{ Deserialize, Serialize };
+
+use crate::telemetry::Encoding;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const HELLO_MESSAGE: &str = "HELLO";
+pub const WELCOME_PREFIX: &[u8] = b"WELCOME ";
+
+pub const FIELD_GROUPS: [&str; 3] = ["physics", "graphics", "static"];
+
+/// What the engineer asks the server for
+#[derive(Debug, Clone, Serialize)]
+pub struct Hello {
+    pub protocol: u32,
+    pub client_version: String,
+    pub encodings: Vec<String>, // Most preferred first
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_hz: Option<f64>,
+    pub field_groups: Vec<String>,
+    pub max_packet_size: usize
+}
+
+impl Hello {
+    /// A hello for every field group at the server's own rate, falling back
+    /// to JSON if the server doesn't have the encoding asked for
+    pub fn new(encoding: Encoding, max_packet_size: usize) -> Hello {
+        let mut encodings = vec![String::from(encoding.name())];
+        if encoding != Encoding::Json {
+            encodings.push(String::from(Encoding::Json.name()));
+        }
+
+        Hello {
+            protocol: PROTOCOL_VERSION,
+            client_version: String::from(env!("CARGO_PKG_VERSION")),
+            encodings,
+            rate_hz: None,
+            field_groups: FIELD_GROUPS.iter().map(|group| String::from(*group)).collect(),
+            max_packet_size
+        }
+    }
+
+    pub fn with_rate(mut self, rate_hz: Option<f64>) -> Hello {
+        self.rate_hz = rate_hz;
+        self
+    }
+
+    pub fn with_field_groups(mut self, field_groups: Vec<String>) -> Hello {
+        self.field_groups = field_groups;
+        self
+    }
+
+    /// The encoding the engineer would most like, for older servers that can
+    /// only be asked for one
+    pub fn preferred_encoding(&self) -> Encoding {
+        self.encodings.first()
+            .and_then(|name| Encoding::from_name(name))
+            .unwrap_or(Encoding::Json)
+    }
+
+    pub fn to_message(&self) -> String {
+        // Only strings and numbers, so this can't fail
+        format!("{} {}", HELLO_MESSAGE, serde_json::to_string(self).unwrap())
+    }
+}
+
+/// What the server agreed to send
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Welcome {
+    pub protocol: u32,
+    #[serde(default)]
+    pub server_version: Option<String>,
+    pub encoding: String,
+    #[serde(default)]
+    pub rate_hz: Option<f64>,
+    #[serde(default)]
+    pub field_groups: Vec<String>,
+    #[serde(default)]
+    pub max_packet_size: Option<usize>
+}
+
+impl Welcome {
+    pub fn parse(datagram: &[u8]) -> Result<Welcome, String> {
+        serde_json::from_slice(&datagram[WELCOME_PREFIX.len()..]).map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Negotiation {
+    Pending,
+    Legacy,
+    Welcomed(Box<Welcome>)
+}
+
+impl fmt::Display for Negotiation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Negotiation::Pending => write!(f, "negotiating"),
+            Negotiation::Legacy => write!(f, "legacy"),
+            Negotiation::Welcomed(welcome) => {
+                write!(f, "v{} {}", welcome.protocol, welcome.encoding)?;
+                if let Some(rate_hz) = welcome.rate_hz {
+                    write!(f, " {:.0} Hz", rate_hz)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
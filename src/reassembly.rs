@@ -0,0 +1,236 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Reassembly
+//
+// A server may split a message too large for one datagram into chunks, each
+// starting with a text header line and followed by its share of the bytes:
+//
+//     CHUNK <message id> <chunk index> <chunk count>\n<bytes>
+//
+// Chunks can arrive in any order. A message is handed on once all of its
+// chunks are in, and given up on if they don't all turn up in time.
+//
+
+use std::collections::HashMap;
+use std::time::{ Duration, Instant };
+
+pub const CHUNK_PREFIX: &[u8] = b"CHUNK ";
+
+pub const MAX_CHUNKS: usize = 64;
+const MAX_PARTIAL_MESSAGES: usize = 8;
+const CHUNK_TIMEOUT: Duration = Duration::from_millis(1000);
+
+pub struct ChunkHeader<'a> {
+    pub message_id: u64,
+    pub index: usize,
+    pub count: usize,
+    pub payload: &'a [u8]
+}
+
+/// Splits a chunk into its header and payload, or None if the header is
+/// malformed
+pub fn parse_chunk(datagram: &[u8]) -> Option<ChunkHeader<'_>> {
+    let header_end = datagram.iter().position(|byte| *byte == b'\n')?;
+    let header = std::str::from_utf8(&datagram[CHUNK_PREFIX.len()..header_end]).ok()?;
+
+    let fields: Vec<u64> = header.split_whitespace()
+        .map(|field| field.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+
+    let (message_id, index, count) = match fields[..] {
+        [message_id, index, count] => (message_id, index as usize, count as usize),
+        _ => { return None; }
+    };
+
+    if count == 0 || count > MAX_CHUNKS || index >= count {
+        return None;
+    }
+
+    Some(ChunkHeader {
+        message_id,
+        index,
+        count,
+        payload: &datagram[header_end + 1..]
+    })
+}
+
+/// Splits a message into chunks of at most chunk_size bytes, header
+/// included, or None if it would take more than MAX_CHUNKS of them
+pub fn split_into_chunks(message: &[u8], message_id: u64, chunk_size: usize) -> Option<Vec<Vec<u8>>> {
+    // Every header is sized as the longest this message's could be
+    let header_room = CHUNK_PREFIX.len() + format!("{} {} {}\n", message_id, MAX_CHUNKS, MAX_CHUNKS).len();
+    let payload_size = chunk_size.checked_sub(header_room).filter(|size| *size > 0)?;
+
+    let count = (message.len() + payload_size - 1) / payload_size;
+    if count == 0 || count > MAX_CHUNKS {
+        return None;
+    }
+
+    let chunks = message.chunks(payload_size).enumerate().map(|(index, payload)| {
+        let mut chunk = CHUNK_PREFIX.to_vec();
+        chunk.extend_from_slice(format!("{} {} {}\n", message_id, index, count).as_bytes());
+        chunk.extend_from_slice(payload);
+        chunk
+    }).collect();
+
+    Some(chunks)
+}
+
+struct PartialMessage {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    started: Instant
+}
+
+pub struct Reassembler {
+    partial: HashMap<u64, PartialMessage>
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler {
+            partial: HashMap::new()
+        }
+    }
+
+    /// Stores a chunk, returning the whole message if this was the last one
+    /// missing
+    pub fn add(&mut self, chunk: ChunkHeader) -> Option<Vec<u8>> {
+        let message = self.partial.entry(chunk.message_id).or_insert_with(|| PartialMessage {
+            chunks: vec![None; chunk.count],
+            received: 0,
+            started: Instant::now()
+        });
+
+        // A chunk count that disagrees with the first chunk's is ignored
+        let slot = message.chunks.get_mut(chunk.index)?;
+        if slot.is_none() {
+            *slot = Some(chunk.payload.to_vec());
+            message.received += 1;
+        }
+
+        if message.received < message.chunks.len() {
+            return None;
+        }
+
+        let message = self.partial.remove(&chunk.message_id)?;
+        Some(message.chunks.into_iter().flatten().flatten().collect())
+    }
+
+    /// Forgets messages whose chunks stopped arriving, and the oldest ones
+    /// if too many are in flight. Returns how many were given up on.
+    pub fn expire(&mut self) -> u64 {
+        let before = self.partial.len();
+        self.partial.retain(|_, message| message.started.elapsed() < CHUNK_TIMEOUT);
+
+        while self.partial.len() > MAX_PARTIAL_MESSAGES {
+            let oldest = self.partial.iter()
+                .min_by_key(|(_, message)| message.started)
+                .map(|(id, _)| *id);
+
+            match oldest {
+                Some(id) => { self.partial.remove(&id); },
+                None => break
+            }
+        }
+
+        (before - self.partial.len()) as u64
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Reassembler {
+        Reassembler::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_chunks() {
+        let chunk = parse_chunk(b"CHUNK 7 1 3\n{\"a\":1}").unwrap();
+        assert_eq!(chunk.message_id, 7);
+        assert_eq!(chunk.index, 1);
+        assert_eq!(chunk.count, 3);
+        assert_eq!(chunk.payload, b"{\"a\":1}");
+
+        // Payloads are bytes, newlines and all
+        let chunk = parse_chunk(b"CHUNK 7 0 1\n\n\x80").unwrap();
+        assert_eq!(chunk.payload, b"\n\x80");
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        assert!(parse_chunk(b"CHUNK 7 0 1").is_none());
+        assert!(parse_chunk(b"CHUNK 7 0\nabc").is_none());
+        assert!(parse_chunk(b"CHUNK 7 0 1 2\nabc").is_none());
+        assert!(parse_chunk(b"CHUNK 7 x 1\nabc").is_none());
+        assert!(parse_chunk(b"CHUNK 7 -1 1\nabc").is_none());
+        assert!(parse_chunk(b"CHUNK 7 0 0\nabc").is_none());
+        assert!(parse_chunk(b"CHUNK 7 3 3\nabc").is_none());
+        assert!(parse_chunk(b"CHUNK 7 0 65\nabc").is_none());
+    }
+
+    #[test]
+    fn reassembles_chunks_in_any_order() {
+        let mut reassembler = Reassembler::new();
+        assert!(reassembler.add(parse_chunk(b"CHUNK 1 2 3\nghi").unwrap()).is_none());
+        assert!(reassembler.add(parse_chunk(b"CHUNK 1 0 3\nabc").unwrap()).is_none());
+        // Repeats don't count towards the message being complete
+        assert!(reassembler.add(parse_chunk(b"CHUNK 1 0 3\nabc").unwrap()).is_none());
+        assert_eq!(reassembler.add(parse_chunk(b"CHUNK 1 1 3\ndef").unwrap()), Some(b"abcdefghi".to_vec()));
+
+        // Finished messages are forgotten
+        assert!(reassembler.add(parse_chunk(b"CHUNK 1 0 3\nabc").unwrap()).is_none());
+        assert_eq!(reassembler.expire(), 0);
+    }
+
+    #[test]
+    fn keeps_messages_apart() {
+        let mut reassembler = Reassembler::new();
+        assert!(reassembler.add(parse_chunk(b"CHUNK 1 0 2\nab").unwrap()).is_none());
+        assert!(reassembler.add(parse_chunk(b"CHUNK 2 0 2\nxy").unwrap()).is_none());
+        assert_eq!(reassembler.add(parse_chunk(b"CHUNK 2 1 2\nz").unwrap()), Some(b"xyz".to_vec()));
+        assert_eq!(reassembler.add(parse_chunk(b"CHUNK 1 1 2\nc").unwrap()), Some(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn ignores_chunks_past_the_first_chunk_count() {
+        let mut reassembler = Reassembler::new();
+        assert!(reassembler.add(parse_chunk(b"CHUNK 1 0 2\nab").unwrap()).is_none());
+        assert!(reassembler.add(parse_chunk(b"CHUNK 1 3 4\nzz").unwrap()).is_none());
+        assert_eq!(reassembler.add(parse_chunk(b"CHUNK 1 1 2\nc").unwrap()), Some(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn gives_up_on_the_oldest_when_too_many_are_in_flight() {
+        let mut reassembler = Reassembler::new();
+        for message_id in 0..MAX_PARTIAL_MESSAGES as u64 + 2 {
+            let chunk = format!("CHUNK {} 0 2\nab", message_id);
+            assert!(reassembler.add(parse_chunk(chunk.as_bytes()).unwrap()).is_none());
+        }
+        assert_eq!(reassembler.expire(), 2);
+
+        assert!(reassembler.add(parse_chunk(b"CHUNK 0 1 2\nc").unwrap()).is_none());
+        assert_eq!(reassembler.add(parse_chunk(b"CHUNK 9 1 2\nc").unwrap()), Some(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn splits_messages_that_reassemble() {
+        let message: Vec<u8> = (0..=255).collect();
+        let chunks = split_into_chunks(&message, 42, 64).unwrap();
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 64));
+
+        let mut reassembler = Reassembler::new();
+        let mut reassembled = None;
+        for chunk in chunks.iter().rev() {
+            reassembled = reassembler.add(parse_chunk(chunk).unwrap());
+        }
+        assert_eq!(reassembled, Some(message));
+
+        assert!(split_into_chunks(b"abc", 42, 15).is_none());
+        assert!(split_into_chunks(&[0; 4096], 42, 32).is_none());
+    }
+}
@@ -0,0 +1,309 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Receiver
+//
+// Runs the network connection on its own thread so a silent or bursty server
+// never holds up key handling or drawing. Packets are parsed on that thread
+// and handed over a channel for the UI to pick up once per frame. Messages
+// for the driver, and the request to shut down, go the other way.
+//
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::sync::mpsc::{ self, Receiver, Sender, TryRecvError };
+use std::thread::{ self, JoinHandle };
+use std::time::{ Duration, Instant };
+
+use crate::generator::Generator;
+use crate::messaging::{ Delivery, SentMessage };
+use crate::network::{ ConnectionState, LinkStats, NetworkInfo };
+use crate::protocol::Negotiation;
+use crate::relay::Relay;
+use crate::telemetry::{ Encoding, TelemetryPacket };
+
+const GENERATOR_NAME: &str = "simulation";
+// Messages to the driver kept for showing how they went
+const MESSAGE_HISTORY: usize = 8;
+
+// How often the link stats are passed on to the UI
+const LINK_STATS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A parsed packet along with the bytes it came from, for the recorder
+pub struct ReceivedPacket {
+    pub packet: TelemetryPacket,
+    pub raw: Vec<u8>,
+    pub encoding: Encoding,
+    pub received_at: Instant
+}
+
+impl ReceivedPacket {
+    /// The packet as JSON, which recordings are kept in whatever it arrived as
+    pub fn json(&self) -> Cow<'_, [u8]> {
+        match self.encoding {
+            Encoding::Json => Cow::Borrowed(&self.raw),
+            _ => Cow::Owned(serde_json::to_vec(&self.packet).unwrap_or_default())
+        }
+    }
+}
+
+enum ReceiverEvent {
+    Packet(Box<Result<ReceivedPacket, String>>),
+    State(ConnectionState),
+    Link(Box<LinkStats>),
+    RelayClients(usize),
+    Negotiation(Box<Negotiation>),
+    Delivery(u32, Delivery)
+}
+
+enum ReceiverCommand {
+    Message(u32, String),
+    Shutdown
+}
+
+pub struct NetworkReceiver {
+    events: Receiver<ReceiverEvent>,
+    pub server_ip: String,
+    state: ConnectionState,
+    link: Box<LinkStats>,
+    relay_clients: Option<usize>,
+    negotiation: Option<Negotiation>,
+    commands: Option<Sender<ReceiverCommand>>, // None when there's no server to talk to
+    thread: Option<JoinHandle<()>>,
+    messages: VecDeque<SentMessage>,
+    next_message_id: u32
+}
+
+impl NetworkReceiver {
+    /// Moves the connection onto a background thread that keeps receiving,
+    /// handshaking and sending heartbeats until the receiver is dropped.
+    /// With a relay, every packet is also passed on to its clients.
+    pub fn spawn(network: NetworkInfo, relay: Option<Relay>) -> NetworkReceiver {
+        let (sender, events) = mpsc::channel();
+        let server_ip = network.server_ip.clone();
+        let state = network.state();
+        let link = Box::new(network.link_stats().clone());
+        let relay_clients = relay.as_ref().map(|relay| relay.client_count());
+        let negotiation = Some(network.negotiation().clone());
+        let (commands, command_receiver) = mpsc::channel();
+
+        let thread = thread::Builder::new()
+            .name(String::from("network"))
+            .spawn(move || receive_loop(network, sender, command_receiver, relay))
+            .unwrap();
+
+        NetworkReceiver {
+            events,
+            server_ip,
+            state,
+            link,
+            relay_clients,
+            negotiation,
+            commands: Some(commands),
+            thread: Some(thread),
+            messages: VecDeque::new(),
+            next_message_id: 1
+        }
+    }
+
+    /// Runs the telemetry generator on a background thread, handing out its
+    /// packets exactly as if they had come from a car
+    pub fn spawn_generator(mut generator: Generator) -> NetworkReceiver {
+        let (sender, events) = mpsc::channel();
+        let interval = Duration::from_secs_f64(1.0 / generator.rate_hz());
+
+        thread::Builder::new()
+            .name(String::from("generator"))
+            .spawn(move || loop {
+                let packet = generator.next_packet(interval);
+                let received = serde_json::to_vec(&packet)
+                    .map(|raw| ReceivedPacket { packet, raw, encoding: Encoding::Json, received_at: Instant::now() })
+                    .map_err(|e| e.to_string());
+
+                if sender.send(ReceiverEvent::Packet(Box::new(received))).is_err() {
+                    return;
+                }
+                thread::sleep(interval);
+            })
+            .unwrap();
+
+        NetworkReceiver {
+            events,
+            server_ip: String::from(GENERATOR_NAME),
+            state: ConnectionState::Streaming,
+            link: Box::new(LinkStats::new()),
+            relay_clients: None,
+            negotiation: None,
+            commands: None,
+            thread: None,
+            messages: VecDeque::new(),
+            next_message_id: 1
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    pub fn link_stats(&self) -> &LinkStats {
+        &self.link
+    }
+
+    /// How many engineers are connected to the relay, if relaying
+    pub fn relay_clients(&self) -> Option<usize> {
+        self.relay_clients
+    }
+
+    /// How the handshake went, None for the generator which has no server
+    pub fn negotiation(&self) -> Option<&Negotiation> {
+        self.negotiation.as_ref()
+    }
+
+    /// Hands a message for the driver to the network thread, which keeps
+    /// sending it until it's acknowledged
+    pub fn send_message(&mut self, text: String) {
+        let id = self.next_message_id;
+        self.next_message_id += 1;
+
+        let sent = match &self.commands {
+            Some(commands) => commands.send(ReceiverCommand::Message(id, text.clone())).is_ok(),
+            None => false
+        };
+
+        let delivery = match sent {
+            true => Delivery::Sending,
+            false => Delivery::Failed
+        };
+        self.messages.push_back(SentMessage { id, text, delivery });
+        if self.messages.len() > MESSAGE_HISTORY {
+            self.messages.pop_front();
+        }
+    }
+
+    pub fn last_message(&self) -> Option<&SentMessage> {
+        self.messages.back()
+    }
+
+    /// Says goodbye to the server and waits for the network thread to
+    /// finish. Nothing more is received afterwards.
+    pub fn shutdown(&mut self) {
+        if let Some(commands) = self.commands.take() {
+            let _ = commands.send(ReceiverCommand::Shutdown);
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    /// Everything received since the last call, oldest first
+    pub fn drain(&mut self) -> Vec<Result<ReceivedPacket, String>> {
+        let mut packets = Vec::new();
+
+        loop {
+            match self.events.try_recv() {
+                Ok(ReceiverEvent::Packet(packet)) => packets.push(*packet),
+                Ok(ReceiverEvent::State(state)) => self.state = state,
+                Ok(ReceiverEvent::Link(link)) => self.link = link,
+                Ok(ReceiverEvent::RelayClients(count)) => self.relay_clients = Some(count),
+                Ok(ReceiverEvent::Negotiation(negotiation)) => self.negotiation = Some(*negotiation),
+                Ok(ReceiverEvent::Delivery(id, delivery)) => {
+                    if let Some(message) = self.messages.iter_mut().find(|message| message.id == id) {
+                        message.delivery = delivery;
+                    }
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.state = ConnectionState::Disconnected;
+                    break;
+                }
+            }
+        }
+
+        packets
+    }
+}
+
+// So the server still hears goodbye when the UI unwinds from a panic
+impl Drop for NetworkReceiver {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn receive_loop(mut network: NetworkInfo, sender: Sender<ReceiverEvent>, commands: Receiver<ReceiverCommand>,
+                mut relay: Option<Relay>) {
+    let mut last_state = network.state();
+    let mut last_link_update = Instant::now();
+    let mut last_relay_clients = relay.as_ref().map(|relay| relay.client_count());
+    let mut last_negotiation = network.negotiation().clone();
+
+    loop {
+        let received = network.recv(relay.as_mut());
+
+        if network.state() != last_state {
+            last_state = network.state();
+            if sender.send(ReceiverEvent::State(last_state)).is_err() {
+                return;
+            }
+        }
+
+        if *network.negotiation() != last_negotiation {
+            last_negotiation = network.negotiation().clone();
+            if sender.send(ReceiverEvent::Negotiation(Box::new(last_negotiation.clone()))).is_err() {
+                return;
+            }
+        }
+
+        if let Some(relay) = relay.as_mut() {
+            relay.poll();
+
+            if Some(relay.client_count()) != last_relay_clients {
+                last_relay_clients = Some(relay.client_count());
+                if sender.send(ReceiverEvent::RelayClients(relay.client_count())).is_err() {
+                    return;
+                }
+            }
+        }
+
+        if let Some(raw) = received {
+            let encoding = Encoding::detect(&raw);
+            let packet = TelemetryPacket::decode(&raw)
+                .map(|packet| ReceivedPacket { packet, raw, encoding, received_at: Instant::now() });
+
+            match &packet {
+                Ok(received) => network.record_packet_id(received.packet.packet_id()),
+                Err(_) => network.record_parse_failure()
+            }
+
+            // The UI has gone away, so there is nobody left to receive for
+            if sender.send(ReceiverEvent::Packet(Box::new(packet))).is_err() {
+                return;
+            }
+        }
+
+        if last_link_update.elapsed() >= LINK_STATS_INTERVAL {
+            last_link_update = Instant::now();
+            if sender.send(ReceiverEvent::Link(Box::new(network.link_stats().clone()))).is_err() {
+                return;
+            }
+        }
+
+        while let Ok(command) = commands.try_recv() {
+            match command {
+                ReceiverCommand::Message(id, text) => network.queue_message(id, text),
+                ReceiverCommand::Shutdown => {
+                    network.send_goodbye();
+                    return;
+                }
+            }
+        }
+        network.send_messages();
+
+        for (id, delivery) in network.take_deliveries() {
+            if sender.send(ReceiverEvent::Delivery(id, delivery)).is_err() {
+                return;
+            }
+        }
+
+        network.send_heartbeat();
+    }
+}
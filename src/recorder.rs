@@ -0,0 +1,200 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Recorder
+//
+// Sessions are written as JSON lines: a header line describing the session
+// followed by one line per packet, each tagged with the milliseconds between
+// its arrival and the arrival of the first recorded packet.
+//
+
+use std::fs::{ self, File };
+use std::io::{ self, BufWriter, Write };
+use std::path::{ Path, PathBuf };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use serde::{ Deserialize, Serialize };
+
+use crate::telemetry::StaticData;
+
+pub const RECORDING_FORMAT: &str = "accrt-recording";
+pub const RECORDING_VERSION: u32 = 1;
+
+const FLUSH_INTERVAL: Duration = Duration::from_millis(1000);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RecordingHeader {
+    pub format: String,
+    pub version: u32,
+    pub date: String,
+    pub car_model: String,
+    pub track: String,
+    pub static_data: StaticData
+}
+
+pub struct SessionRecorder {
+    directory: PathBuf,
+    label: Option<String>,
+    path: Option<PathBuf>,
+    writer: Option<BufWriter<File>>,
+    started: Instant,
+    last_flush: Instant,
+    packets: u64,
+    error: Option<io::Error>
+}
+
+impl SessionRecorder {
+    pub fn new(directory: &Path) -> SessionRecorder {
+        SessionRecorder {
+            directory: directory.to_path_buf(),
+            label: None,
+            path: None,
+            writer: None,
+            started: Instant::now(),
+            last_flush: Instant::now(),
+            packets: 0,
+            error: None
+        }
+    }
+
+    /// Adds a label to the file name, to tell apart cars recorded side by side
+    pub fn with_label(mut self, label: &str) -> SessionRecorder {
+        self.label = Some(sanitize(label));
+        self
+    }
+
+    /// Appends a packet to the recording, opening the file and writing the
+    /// header from the first packet's static data if this is the first one.
+    /// Packets are timed by when they arrived, not when they are written.
+    /// After a failed write the recorder stops and reports the error instead.
+    pub fn record(&mut self, raw_packet: &[u8], statics: &StaticData, received_at: Instant) {
+        if self.error.is_some() {
+            return;
+        }
+
+        if let Err(e) = self.try_record(raw_packet, statics, received_at) {
+            self.error = Some(e);
+            self.writer = None;
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Some(writer) = self.writer.as_mut() {
+            if let Err(e) = writer.flush() {
+                self.error = Some(e);
+            }
+        }
+        self.last_flush = Instant::now();
+    }
+
+    /// Short description for the status line
+    pub fn status(&self) -> String {
+        match (&self.error, &self.path) {
+            (Some(e), _) => format!("REC failed: {}", e),
+            (None, Some(path)) => format!("REC {} ({} packets)", path.display(), self.packets),
+            (None, None) => String::from("REC waiting for data")
+        }
+    }
+
+    fn try_record(&mut self, raw_packet: &[u8], statics: &StaticData, received_at: Instant) -> io::Result<()> {
+        if self.writer.is_none() {
+            self.open(statics, received_at)?;
+        }
+
+        let elapsed_ms = received_at.saturating_duration_since(self.started).as_millis();
+        let writer = self.writer.as_mut().unwrap();
+
+        // The packet has already parsed as JSON, so it is embedded verbatim,
+        // unless it was pretty printed across lines and would split the frame
+        write!(writer, "{{\"t_ms\":{},\"packet\":", elapsed_ms)?;
+        match raw_packet.contains(&b'\n') {
+            true => {
+                let packet: serde_json::Value = serde_json::from_slice(raw_packet)?;
+                serde_json::to_writer(&mut *writer, &packet)?;
+            },
+            false => writer.write_all(raw_packet)?
+        }
+        writer.write_all(b"}\n")?;
+        self.packets += 1;
+
+        if self.last_flush.elapsed() > FLUSH_INTERVAL {
+            self.flush();
+        }
+
+        Ok(())
+    }
+
+    fn open(&mut self, statics: &StaticData, received_at: Instant) -> io::Result<()> {
+        fs::create_dir_all(&self.directory)?;
+
+        let now = SystemTime::now();
+        let file_name = match &self.label {
+            Some(label) => format!("{}_{}_{}.jsonl", file_timestamp(now), sanitize(&statics.track), label),
+            None => format!("{}_{}.jsonl", file_timestamp(now), sanitize(&statics.track))
+        };
+        let path = self.directory.join(file_name);
+
+        let mut writer = BufWriter::new(File::create(&path)?);
+
+        let header = RecordingHeader {
+            format: String::from(RECORDING_FORMAT),
+            version: RECORDING_VERSION,
+            date: iso_timestamp(now),
+            car_model: statics.car_model.clone(),
+            track: statics.track.clone(),
+            static_data: statics.clone()
+        };
+        serde_json::to_writer(&mut writer, &header)?;
+        writer.write_all(b"\n")?;
+
+        self.started = received_at;
+        self.writer = Some(writer);
+        self.path = Some(path);
+
+        Ok(())
+    }
+}
+
+impl Drop for SessionRecorder {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+fn sanitize(name: &str) -> String {
+    let cleaned: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+
+    if cleaned.is_empty() { String::from("session") } else { cleaned }
+}
+
+fn file_timestamp(time: SystemTime) -> String {
+    let (year, month, day, hour, min, sec) = utc_fields(time);
+    format!("{:04}{:02}{:02}_{:02}{:02}{:02}", year, month, day, hour, min, sec)
+}
+
+fn iso_timestamp(time: SystemTime) -> String {
+    let (year, month, day, hour, min, sec) = utc_fields(time);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, min, sec)
+}
+
+// Converts to a UTC calendar date without pulling in a date crate,
+// using the days-to-civil algorithm from Howard Hinnant
+fn utc_fields(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as i64;
+
+    let days = secs.div_euclid(86400);
+    let day_secs = secs.rem_euclid(86400) as u32;
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day, day_secs / 3600, (day_secs / 60) % 60, day_secs % 60)
+}
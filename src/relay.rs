@@ -0,0 +1,108 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Relay
+//
+// Passes the driver's telemetry on to other engineers, so the driver's PC
+// only ever sends it once. Downstream engineers connect to the relay exactly
+// as they would to ACCRT: handshake, heartbeats and pings are all answered.
+// Hellos are accepted but not welcomed, as the relay passes on whatever the
+// driver's server sends and can't promise anything about it. With a shared
+// key, clients must sign what they send and everything they get is signed.
+//
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+use std::net::{ SocketAddr, UdpSocket };
+use std::time::Instant;
+
+use crate::auth::Authenticator;
+use crate::network::{ self, CLIENT_TIMEOUT, GOODBYE_MESSAGE, HANDSHAKE_MESSAGE, HEARTBEAT_MESSAGE, PING_MESSAGE,
+                      PONG_MESSAGE };
+use crate::protocol::HELLO_MESSAGE;
+
+// Room for a signed hello
+const CONTROL_BUFFER_SIZE: usize = 512;
+
+pub struct Relay {
+    socket: UdpSocket,
+    clients: HashMap<SocketAddr, Instant>,
+    auth: Option<Authenticator>
+}
+
+impl Relay {
+    pub fn bind(listen_ip: &str) -> io::Result<Relay> {
+        let socket = UdpSocket::bind(listen_ip)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Relay {
+            socket,
+            clients: HashMap::new(),
+            auth: None
+        })
+    }
+
+    pub fn with_authenticator(mut self, auth: Option<Authenticator>) -> Relay {
+        self.auth = auth;
+        self
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Handles whatever the downstream engineers have sent since the last
+    /// call, and forgets any that have gone quiet
+    pub fn poll(&mut self) {
+        let mut buffer = [0; CONTROL_BUFFER_SIZE];
+
+        // Errors are typically a client having gone away (ICMP unreachable),
+        // which the timeout below deals with
+        while let Ok((buf_size, client)) = self.socket.recv_from(&mut buffer) {
+            // Clients without the key are ignored, as the server would
+            let datagram = match &self.auth {
+                Some(auth) => match auth.verify(&buffer[..buf_size]) {
+                    Some(val) => val,
+                    None => continue
+                },
+                None => &buffer[..buf_size]
+            };
+            let message = String::from_utf8_lossy(datagram);
+            self.on_message(message.trim(), client);
+        }
+
+        self.clients.retain(|_, last_heard| last_heard.elapsed() < CLIENT_TIMEOUT);
+    }
+
+    /// Sends a datagram from the driver's server on to every client,
+    /// chunks included, so they reassemble it as we did
+    pub fn forward(&mut self, datagram: &[u8]) {
+        let datagram = self.sign(datagram);
+        for client in self.clients.keys() {
+            let _ = self.socket.send_to(&datagram, client);
+        }
+    }
+
+    fn on_message(&mut self, message: &str, client: SocketAddr) {
+        if message == HANDSHAKE_MESSAGE || message == HEARTBEAT_MESSAGE || message.starts_with(HELLO_MESSAGE) {
+            self.clients.insert(client, Instant::now());
+        }
+        else if message == GOODBYE_MESSAGE {
+            self.clients.remove(&client);
+        }
+        else if let Some(ping) = message.strip_prefix(PING_MESSAGE) {
+            // The client's own clock is echoed back for it to work out the
+            // round trip, this one is the relay's
+            let pong = format!("{}{} {}", PONG_MESSAGE, ping, network::unix_ms());
+            let _ = self.socket.send_to(&self.sign(pong.as_bytes()), client);
+            self.clients.insert(client, Instant::now());
+        }
+    }
+
+    fn sign<'a>(&self, datagram: &'a [u8]) -> Cow<'a, [u8]> {
+        match &self.auth {
+            Some(auth) => Cow::Owned(auth.sign(datagram)),
+            None => Cow::Borrowed(datagram)
+        }
+    }
+}
@@ -0,0 +1,205 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Replay
+//
+// Plays back a session written by the recorder, handing out packets at the
+// pace they were received (scaled by the playback speed).
+//
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{ BufRead, BufReader };
+use std::path::Path;
+use std::time::Instant;
+
+use serde::Deserialize;
+
+use crate::recorder::{ RecordingHeader, RECORDING_FORMAT };
+use crate::telemetry::TelemetryPacket;
+
+const PLAYBACK_SPEEDS: [f64; 6] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+const DEFAULT_SPEED_INDEX: usize = 2;
+
+#[derive(Deserialize)]
+struct ReplayFrame {
+    t_ms: u64,
+    packet: TelemetryPacket
+}
+
+pub struct ReplaySource {
+    header: RecordingHeader,
+    frames: Vec<ReplayFrame>,
+    cursor: usize, // Index of the next frame to hand out
+    position_ms: f64,
+    speed_index: usize,
+    paused: bool,
+    last_tick: Instant
+}
+
+impl ReplaySource {
+    pub fn open(path: &Path) -> Result<ReplaySource, Box<dyn Error>> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+
+        let header: RecordingHeader = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => { return Err(format!("{} is empty", path.display()).into()); }
+        };
+
+        if header.format != RECORDING_FORMAT {
+            return Err(format!("{} is not an ACCRT recording", path.display()).into());
+        }
+
+        let mut frames: Vec<ReplayFrame> = Vec::new();
+        for (index, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            // Line numbers are 1-based and the header takes the first line
+            let frame: ReplayFrame = serde_json::from_str(&line)
+                .map_err(|e| format!("{} line {}: {}", path.display(), index + 2, e))?;
+            frames.push(frame);
+        }
+
+        if frames.is_empty() {
+            return Err(format!("{} has no packets", path.display()).into());
+        }
+
+        Ok(ReplaySource {
+            header,
+            frames,
+            cursor: 0,
+            position_ms: 0.0,
+            speed_index: DEFAULT_SPEED_INDEX,
+            paused: false,
+            last_tick: Instant::now()
+        })
+    }
+
+    /// Advances the playback clock and returns every packet that fell due
+    /// since the last call, oldest first. None are skipped at high speeds, so
+    /// lap and fuel figures come out the same as they did live.
+    pub fn next_packets(&mut self) -> Vec<TelemetryPacket> {
+        let now = Instant::now();
+        if !self.paused {
+            let elapsed_ms = now.duration_since(self.last_tick).as_secs_f64() * 1000.0;
+            self.position_ms = (self.position_ms + elapsed_ms * self.speed())
+                .min(self.duration_ms() as f64);
+        }
+        self.last_tick = now;
+
+        let due = self.frames.partition_point(|frame| frame.t_ms as f64 <= self.position_ms);
+        if due <= self.cursor {
+            return Vec::new();
+        }
+
+        let packets = self.frames[self.cursor..due].iter().map(|frame| frame.packet.clone()).collect();
+        self.cursor = due;
+        packets
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    pub fn speed_up(&mut self) {
+        self.speed_index = std::cmp::min(self.speed_index + 1, PLAYBACK_SPEEDS.len() - 1);
+    }
+
+    pub fn slow_down(&mut self) {
+        self.speed_index = self.speed_index.saturating_sub(1);
+    }
+
+    /// Moves playback to the first packet after the current lap is completed
+    pub fn skip_to_next_lap(&mut self) {
+        let current = self.cursor.saturating_sub(1);
+        let current_lap = self.frames[current].packet.graphics_data.completed_laps;
+
+        let next_lap = self.frames[current..].iter()
+            .find(|frame| frame.packet.graphics_data.completed_laps > current_lap);
+
+        if let Some(frame) = next_lap {
+            self.seek(frame.t_ms);
+        }
+    }
+
+    /// Jumps to a point in the recording, re-delivering the packet at that point
+    pub fn seek(&mut self, target_ms: u64) {
+        let target_ms = std::cmp::min(target_ms, self.duration_ms());
+        let due = self.frames.partition_point(|frame| frame.t_ms <= target_ms);
+
+        self.position_ms = target_ms as f64;
+        self.cursor = due.saturating_sub(1);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.cursor >= self.frames.len()
+    }
+
+    /// Short description for the status line
+    pub fn status(&self) -> String {
+        let paused = if self.paused { " PAUSED" } else if self.is_finished() { " END" } else { "" };
+
+        format!("REPLAY {} @ {} {} / {} x{}{}",
+                self.header.car_model,
+                self.header.track,
+                format_timestamp(self.position_ms as u64),
+                format_timestamp(self.duration_ms()),
+                self.speed(),
+                paused)
+    }
+
+    fn speed(&self) -> f64 {
+        PLAYBACK_SPEEDS[self.speed_index]
+    }
+
+    fn duration_ms(&self) -> u64 {
+        self.frames.last().map(|frame| frame.t_ms).unwrap_or(0)
+    }
+}
+
+fn format_timestamp(ms: u64) -> String {
+    let secs = ms / 1000;
+    format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+/// Parses "h:mm:ss", "mm:ss" or plain seconds into milliseconds. Times too
+/// long to represent are rejected like any other bad input.
+pub fn parse_timestamp(input: &str) -> Option<u64> {
+    let mut secs: u64 = 0;
+
+    for part in input.trim().split(':') {
+        secs = secs.checked_mul(60)?.checked_add(part.trim().parse::<u64>().ok()?)?;
+    }
+
+    secs.checked_mul(1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_each_form() {
+        assert_eq!(parse_timestamp("42"), Some(42000));
+        assert_eq!(parse_timestamp("3:05"), Some(185000));
+        assert_eq!(parse_timestamp("1:02:03"), Some(3723000));
+        assert_eq!(parse_timestamp(" 1 : 02 : 03 "), Some(3723000));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert_eq!(parse_timestamp(""), None);
+        assert_eq!(parse_timestamp("1:xx"), None);
+        assert_eq!(parse_timestamp("-5"), None);
+        assert_eq!(parse_timestamp("1::2"), None);
+    }
+
+    #[test]
+    fn rejects_overflow() {
+        assert_eq!(parse_timestamp("99999999999:0"), Some(99999999999 * 60 * 1000));
+        assert_eq!(parse_timestamp("99999999999:0:0:0:0"), None);
+        assert_eq!(parse_timestamp("18446744073709551615"), None);
+    }
+}
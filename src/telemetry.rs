@@ -0,0 +1,428 @@
+// James Robertson 2022
+// ACCRT Engineer Rust
+// Telemetry
+//
+
+use std::fmt;
+
+use serde::{ Deserialize, Deserializer, Serialize };
+use serde_json::{ json, Value };
+
+/// Per-wheel values in ACC order: front left, front right, rear left, rear right
+pub type Wheels<T> = [T; 4];
+
+/// A single datagram from ACCRT, deserialized once and handed to the blocks.
+/// Groups the engineer didn't ask the server for are left at their defaults.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TelemetryPacket {
+    #[serde(default)]
+    pub physics_data: PhysicsData,
+    #[serde(default)]
+    pub graphics_data: GraphicsData,
+    #[serde(default)]
+    pub static_data: StaticData
+}
+
+impl TelemetryPacket {
+    /// Decodes a packet in whichever encoding it turns out to be in
+    pub fn decode(bytes: &[u8]) -> Result<TelemetryPacket, String> {
+        match Encoding::detect(bytes) {
+            Encoding::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Encoding::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+            Encoding::Cbor => ciborium::de::from_reader(bytes).map_err(|e| e.to_string())
+        }
+    }
+
+    /// The id to tell packets apart by. That's physics', unless the server
+    /// wasn't asked for physics and left it at 0, in which case it's
+    /// graphics'. Outside a session ACC leaves both at 0.
+    pub fn packet_id(&self) -> u64 {
+        match self.physics_data.packet_id {
+            0 => self.graphics_data.packet_id,
+            packet_id => packet_id
+        }
+    }
+
+    pub fn in_session(&self) -> bool {
+        self.packet_id() != 0
+    }
+}
+
+/// The wire formats a server can send packets in. JSON is what every server
+/// speaks, the binary ones carry the same structure in fewer bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Cbor
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "json" => Some(Encoding::Json),
+            "msgpack" => Some(Encoding::MessagePack),
+            "cbor" => Some(Encoding::Cbor),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Json        => "json",
+            Encoding::MessagePack => "msgpack",
+            Encoding::Cbor        => "cbor"
+        }
+    }
+
+    /// Encodes a packet as a server sends it. Maps keep their keys in
+    /// MessagePack, as decoding expects.
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Encoding::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(value, &mut bytes).map_err(|e| e.to_string())?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Tells the encodings apart by the first byte, which for a packet is
+    /// always the start of a map and is different in each. Anything else is
+    /// left to the JSON parser to complain about.
+    pub fn detect(bytes: &[u8]) -> Encoding {
+        match bytes.first() {
+            Some(0x80..=0x8f) | Some(0xde) | Some(0xdf) => Encoding::MessagePack,
+            Some(0xa0..=0xbb) | Some(0xbf) => Encoding::Cbor,
+            _ => Encoding::Json
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PhysicsData {
+    pub packet_id: u64,
+    pub gas: f64,
+    pub brake: f64,
+    pub fuel: f64,
+    pub gear: u8, // 0 is reverse, 1 is neutral
+    pub rpms: u64,
+    pub speed_kmh: f64,
+    pub tyre_temp: Wheels<f64>,
+    pub wheels_pressure: Wheels<f64>,
+    pub brake_temp: Wheels<f64>,
+    pub air_temp: f64,
+    pub road_temp: f64,
+    // Channels not modelled above, kept so custom blocks can reach them
+    #[serde(flatten)]
+    pub other_channels: serde_json::Map<String, serde_json::Value>
+}
+
+impl PhysicsData {
+    /// A channel by its ACCRT name, modelled or not, for blocks that pick
+    /// their channels from the config
+    pub fn channel(&self, name: &str) -> Option<Value> {
+        let value = match name {
+            "packetId"       => json!(self.packet_id),
+            "gas"            => json!(self.gas),
+            "brake"          => json!(self.brake),
+            "fuel"           => json!(self.fuel),
+            "gear"           => json!(self.gear),
+            "rpms"           => json!(self.rpms),
+            "speedKmh"       => json!(self.speed_kmh),
+            "tyreTemp"       => json!(self.tyre_temp),
+            "wheelsPressure" => json!(self.wheels_pressure),
+            "brakeTemp"      => json!(self.brake_temp),
+            "airTemp"        => json!(self.air_temp),
+            "roadTemp"       => json!(self.road_temp),
+            _ => { return self.other_channels.get(name).cloned(); }
+        };
+        Some(value)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphicsData {
+    pub packet_id: u64,
+    pub status: Status,
+    pub session: SessionType,
+    pub current_time: String,
+    pub last_time: String,
+    pub best_time: String,
+    pub completed_laps: u32,
+    pub position: u32,
+    pub i_current_time: i64,
+    pub i_last_time: i64,
+    pub i_best_time: i64,
+    pub session_time_left: f64, // Milliseconds
+    #[serde(deserialize_with = "bool_from_int")]
+    pub is_in_pit: bool,
+    pub current_sector_index: u32,
+    pub last_sector_time: i64,
+    pub number_of_laps: u32,
+    pub normalized_car_position: f64,
+    pub flag: FlagType,
+    pub fuel_x_lap: f64,
+    pub used_fuel: f64,
+    #[serde(deserialize_with = "bool_from_int")]
+    pub is_valid_lap: bool,
+    #[serde(flatten)]
+    pub other_channels: serde_json::Map<String, serde_json::Value>
+}
+
+impl GraphicsData {
+    /// A channel by its ACCRT name, modelled or not, for blocks that pick
+    /// their channels from the config
+    pub fn channel(&self, name: &str) -> Option<Value> {
+        let value = match name {
+            "packetId"              => json!(self.packet_id),
+            "status"                => json!(i32::from(self.status)),
+            "session"               => json!(i32::from(self.session)),
+            "currentTime"           => json!(self.current_time),
+            "lastTime"              => json!(self.last_time),
+            "bestTime"              => json!(self.best_time),
+            "completedLaps"         => json!(self.completed_laps),
+            "position"              => json!(self.position),
+            "iCurrentTime"          => json!(self.i_current_time),
+            "iLastTime"             => json!(self.i_last_time),
+            "iBestTime"             => json!(self.i_best_time),
+            "sessionTimeLeft"       => json!(self.session_time_left),
+            "isInPit"               => json!(self.is_in_pit),
+            "currentSectorIndex"    => json!(self.current_sector_index),
+            "lastSectorTime"        => json!(self.last_sector_time),
+            "numberOfLaps"          => json!(self.number_of_laps),
+            "normalizedCarPosition" => json!(self.normalized_car_position),
+            "flag"                  => json!(i32::from(self.flag)),
+            "fuelXLap"              => json!(self.fuel_x_lap),
+            "usedFuel"              => json!(self.used_fuel),
+            "isValidLap"            => json!(self.is_valid_lap),
+            _ => { return self.other_channels.get(name).cloned(); }
+        };
+        Some(value)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StaticData {
+    pub car_model: String,
+    pub track: String,
+    pub player_name: String,
+    pub player_surname: String,
+    pub sector_count: u32,
+    pub max_rpm: u64,
+    pub max_fuel: f64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(from = "i32", into = "i32")]
+pub enum Status {
+    #[default]
+    Off,
+    Replay,
+    Live,
+    Pause,
+    Unknown(i32)
+}
+
+impl From<i32> for Status {
+    fn from(value: i32) -> Status {
+        match value {
+            0 => Status::Off,
+            1 => Status::Replay,
+            2 => Status::Live,
+            3 => Status::Pause,
+            _ => Status::Unknown(value)
+        }
+    }
+}
+
+impl From<Status> for i32 {
+    fn from(value: Status) -> i32 {
+        match value {
+            Status::Off            => 0,
+            Status::Replay         => 1,
+            Status::Live           => 2,
+            Status::Pause          => 3,
+            Status::Unknown(value) => value
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(from = "i32", into = "i32")]
+pub enum SessionType {
+    #[default]
+    Unknown,
+    Practice,
+    Qualify,
+    Race,
+    Hotlap,
+    TimeAttack,
+    Drift,
+    Drag,
+    HotStint,
+    HotlapSuperpole,
+    Other(i32)
+}
+
+impl From<i32> for SessionType {
+    fn from(value: i32) -> SessionType {
+        match value {
+            -1 => SessionType::Unknown,
+            0  => SessionType::Practice,
+            1  => SessionType::Qualify,
+            2  => SessionType::Race,
+            3  => SessionType::Hotlap,
+            4  => SessionType::TimeAttack,
+            5  => SessionType::Drift,
+            6  => SessionType::Drag,
+            7  => SessionType::HotStint,
+            8  => SessionType::HotlapSuperpole,
+            _  => SessionType::Other(value)
+        }
+    }
+}
+
+impl From<SessionType> for i32 {
+    fn from(value: SessionType) -> i32 {
+        match value {
+            SessionType::Unknown         => -1,
+            SessionType::Practice        => 0,
+            SessionType::Qualify         => 1,
+            SessionType::Race            => 2,
+            SessionType::Hotlap          => 3,
+            SessionType::TimeAttack      => 4,
+            SessionType::Drift           => 5,
+            SessionType::Drag            => 6,
+            SessionType::HotStint        => 7,
+            SessionType::HotlapSuperpole => 8,
+            SessionType::Other(value)    => value
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(from = "i32", into = "i32")]
+pub enum FlagType {
+    #[default]
+    None,
+    Blue,
+    Yellow,
+    Black,
+    White,
+    Checkered,
+    Penalty,
+    Green,
+    Orange,
+    Unknown(i32)
+}
+
+impl From<i32> for FlagType {
+    fn from(value: i32) -> FlagType {
+        match value {
+            0 => FlagType::None,
+            1 => FlagType::Blue,
+            2 => FlagType::Yellow,
+            3 => FlagType::Black,
+            4 => FlagType::White,
+            5 => FlagType::Checkered,
+            6 => FlagType::Penalty,
+            7 => FlagType::Green,
+            8 => FlagType::Orange,
+            _ => FlagType::Unknown(value)
+        }
+    }
+}
+
+impl From<FlagType> for i32 {
+    fn from(value: FlagType) -> i32 {
+        match value {
+            FlagType::None           => 0,
+            FlagType::Blue           => 1,
+            FlagType::Yellow         => 2,
+            FlagType::Black          => 3,
+            FlagType::White          => 4,
+            FlagType::Checkered      => 5,
+            FlagType::Penalty        => 6,
+            FlagType::Green          => 7,
+            FlagType::Orange         => 8,
+            FlagType::Unknown(value) => value
+        }
+    }
+}
+
+// ACC exposes its booleans as ints, but accept real booleans as well
+fn bool_from_int<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum IntOrBool {
+        Int(i64),
+        Bool(bool)
+    }
+
+    match IntOrBool::deserialize(deserializer)? {
+        IntOrBool::Int(val) => Ok(val != 0),
+        IntOrBool::Bool(val) => Ok(val)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every serialized channel must be reachable under the same name
+    fn assert_channels_match(serialized: Value, channel: impl Fn(&str) -> Option<Value>) {
+        for (name, value) in serialized.as_object().unwrap() {
+            assert_eq!(channel(name).as_ref(), Some(value), "channel {}", name);
+        }
+    }
+
+    #[test]
+    fn physics_channels_match_serde_names() {
+        let mut physics = PhysicsData { packet_id: 7, gear: 3, tyre_temp: [80.0, 81.0, 82.0, 83.0], ..Default::default() };
+        physics.other_channels.insert(String::from("tc"), json!(2));
+
+        assert_channels_match(serde_json::to_value(&physics).unwrap(), |name| physics.channel(name));
+        assert_eq!(physics.channel("tc"), Some(json!(2)));
+        assert_eq!(physics.channel("noSuchChannel"), None);
+    }
+
+    #[test]
+    fn graphics_channels_match_serde_names() {
+        let mut graphics = GraphicsData {
+            status: Status::Live,
+            flag: FlagType::Checkered,
+            is_in_pit: true,
+            completed_laps: 4,
+            ..Default::default()
+        };
+        graphics.other_channels.insert(String::from("windSpeed"), json!(3.5));
+
+        assert_channels_match(serde_json::to_value(&graphics).unwrap(), |name| graphics.channel(name));
+        assert_eq!(graphics.channel("windSpeed"), Some(json!(3.5)));
+    }
+
+    #[test]
+    fn packet_id_falls_back_on_graphics() {
+        let mut packet = TelemetryPacket::default();
+        assert!(!packet.in_session());
+
+        packet.graphics_data.packet_id = 40;
+        assert_eq!(packet.packet_id(), 40);
+        assert!(packet.in_session());
+
+        packet.physics_data.packet_id = 90;
+        assert_eq!(packet.packet_id(), 90);
+    }
+}
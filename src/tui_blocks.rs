@@ -3,19 +3,24 @@
 // TUI Blocks
 //
 
-use crossterm::cursor;
+use std::collections::{ HashMap, VecDeque };
 
-const RED_BLOCK: &str = "\x1b[91;1m▉\x1b[31;0m";
-const WHITE_BLOCK: &str ="▉";
+use tui::backend::CrosstermBackend;
+use tui::layout::{ Constraint, Rect };
+use tui::style::{ Color, Modifier, Style };
+use tui::text::{ Span, Spans };
+use tui::widgets::{ Block, Borders, Cell, Paragraph, Row, Table };
+use tui::Frame;
 
-const RPM_BAR_LEN: usize = 0x11;
+use crate::config::{ self, CustomBlockConfig };
+use crate::network::LinkStats;
+use crate::telemetry::{ GraphicsData, PhysicsData, StaticData, Wheels };
+
+pub type TermBackend = CrosstermBackend<std::io::Stdout>;
 
-const COLOR_RESET: &str = "\x1b[31;0m";
+const BAR_BLOCK: &str = "▉";
 
-const TEXT_COLOR_COLD: &str = "\x1b[96;1m";
-const TEXT_COLOR_OPTIMAL: &str = "\x1b[92;1m";
-const TEXT_COLOR_WARNING: &str = "\x1b[93;1m";
-const TEXT_COLOR_TOO_HOT: &str = "\x1b[91;1m";
+const RPM_BAR_LEN: usize = 0x11;
 
 // These are rough estimates
 const TYRE_NUM_COLD: f64 = 72.0;
@@ -27,28 +32,65 @@ const BRAKE_NUM_COLD: f64 = 475.0;
 const BRAKE_NUM_OPTIMAL: f64 = 650.0;
 const BRAKE_NUM_WARNING: f64 = 675.0;
 
+// Laps the fuel calculator averages consumption and lap time over
+const FUEL_AVERAGE_LAPS: usize = 5;
+
+const LAP_HISTORY_HEIGHT: u16 = 14;
+const DEFAULT_SECTOR_COUNT: usize = 3;
+
 pub trait TUIBlock {
-    fn update(&mut self, physics: &serde_json::Value, graphics: &serde_json::Value);
-    fn init_statics(&mut self, statics: &serde_json::Value);
-    fn display(&self);
+    fn update(&mut self, physics: &PhysicsData, graphics: &GraphicsData);
+    fn init_statics(&mut self, statics: &StaticData);
+    fn display(&self, frame: &mut Frame<TermBackend>, area: Rect);
+
+    /// Rows the block needs, including its border
+    fn height(&self) -> u16;
+
+    /// Moves a scrollable block's view, negative lines scrolling back up
+    fn scroll(&mut self, _lines: i32) { }
+
+    /// Called with the latest link stats while connected to a live car
+    fn update_link(&mut self, _stats: &LinkStats) { }
 }
 
-pub struct Bounds {
-    start_x: u16,
-    start_y: u16,
-    _len_x: u32,
-    _len_y: u32,
-    _visible: bool
+fn bordered_block(title: &str) -> Block<'_> {
+    Block::default()
+        .borders(Borders::ALL)
+        .title(Span::styled(title, Style::default().add_modifier(Modifier::BOLD)))
 }
 
-impl Bounds {
-    pub fn new(start_x: u16, start_y: u16, _len_x: u32, _len_y: u32) -> Bounds {
-        Bounds {start_x, start_y, _len_x, _len_y, _visible: true}
+fn bold_color(color: Color) -> Style {
+    Style::default().fg(color).add_modifier(Modifier::BOLD)
+}
+
+fn temperature_style(temp: f64, cold: f64, optimal: f64, warning: f64) -> Style {
+    if temp < cold {
+        bold_color(Color::LightCyan)
+    }
+    else if temp < optimal {
+        bold_color(Color::LightGreen)
     }
+    else if temp < warning {
+        bold_color(Color::LightYellow)
+    }
+    else {
+        bold_color(Color::LightRed)
+    }
+}
+
+// Lays the four wheels out as the car is seen from above, front on top
+fn wheel_grid(wheels: Wheels<Span>) -> Vec<Spans> {
+    let [front_left, front_right, rear_left, rear_right] = wheels;
+
+    vec![
+        Spans::from(vec![Span::raw(" "), front_left, Span::raw("  "), front_right]),
+        Spans::default(),
+        Spans::from(vec![Span::raw(" "), rear_left, Span::raw("  "), rear_right])
+    ]
 }
 
+#[derive(Default)]
 pub struct Tachometer {
-    coords: Bounds,
     rpm_cur: u64,
     rpm_max: u64,
     rpm_bar: [bool; RPM_BAR_LEN],
@@ -56,9 +98,8 @@ pub struct Tachometer {
 }
 
 impl Tachometer {
-    pub fn new(x: u16, y: u16) -> Tachometer {
-        return Tachometer {
-            coords: Bounds::new(x, y, 0, 0),
+    pub fn new() -> Tachometer {
+        Tachometer {
             rpm_cur: 0,
             rpm_max: 0,
             rpm_bar: [false; RPM_BAR_LEN],
@@ -66,55 +107,42 @@ impl Tachometer {
         }
     }
 
-    fn print_rpm_bar(&self) {
-        let tachometer_end: &str = "┃";
+    fn rpm_bar(&self) -> Spans<'static> {
+        let tachometer_end = Span::raw("┃");
+
+        let mut spans: Vec<Span> = vec![tachometer_end.clone()];
 
-        print!("{}{}", cursor::MoveTo(self.coords.start_x, self.coords.start_y + 4),
-                       tachometer_end);
-        
         if self.rpm_max < self.rpm_cur || self.rpm_max - self.rpm_cur < 100 {
-            for _i in 0..RPM_BAR_LEN - 1 {
-                print!("{}", RED_BLOCK);
-            }
+            let bar = BAR_BLOCK.repeat(RPM_BAR_LEN - 1);
+            spans.push(Span::styled(bar, bold_color(Color::LightRed)));
         }
         else {
-            for i in 0..(self.rpm_bar.len() - 1) {
-                if self.rpm_bar[i] == true {
-                    print!("{}", WHITE_BLOCK);
-                }
-                else {
-                    print!(" ");
-                }
-            }
+            let bar: String = self.rpm_bar[0..RPM_BAR_LEN - 1].iter()
+                .map(|lit| if *lit { BAR_BLOCK } else { " " })
+                .collect();
+            spans.push(Span::raw(bar));
         }
-        println!("{}", tachometer_end);
+
+        spans.push(tachometer_end);
+        Spans::from(spans)
     }
 }
 
 impl TUIBlock for Tachometer {
-    fn display(&self) {
-        print!("{}Tachometer", 
-               cursor::MoveTo(self.coords.start_x, self.coords.start_y));
-        println!("{}RPM:  {} / {}",
-                 cursor::MoveTo(self.coords.start_x + 2, self.coords.start_y + 1),
-                 self.rpm_cur,
-                 self.rpm_max);
-        println!("{}Gear: {}", 
-                 cursor::MoveTo(self.coords.start_x + 2, self.coords.start_y + 2),
-                 self.gear_char);
-
-        self.print_rpm_bar();
-    }
-
-    fn update(&mut self, physics: &serde_json::Value, _graphics: &serde_json::Value) {
-        let rpm_cur = match physics["rpms"].as_u64() {
-            Some(val) => val,
-            None      => 0
-        };
+    fn display(&self, frame: &mut Frame<TermBackend>, area: Rect) {
+        let text = vec![
+            Spans::from(format!("RPM:  {} / {}", self.rpm_cur, self.rpm_max)),
+            Spans::from(format!("Gear: {}", self.gear_char)),
+            self.rpm_bar()
+        ];
+
+        frame.render_widget(Paragraph::new(text).block(bordered_block("Tachometer")), area);
+    }
 
-        self.rpm_cur = rpm_cur;
+    fn update(&mut self, physics: &PhysicsData, _graphics: &GraphicsData) {
+        self.rpm_cur = physics.rpms;
 
-        let mut gear_int = physics["gear"].as_u64().unwrap() as u8;
+        let mut gear_int = physics.gear;
 
         // Decrement by one to account for reverse starting at 0
         if gear_int >= 1 {
@@ -132,151 +160,96 @@ impl TUIBlock for Tachometer {
             rpm_percentage = self.rpm_bar.len() - 1;
         }
 
-        for i in 0..rpm_percentage as usize {
-            self.rpm_bar[i] = true;
-        }
-        for i in rpm_percentage..self.rpm_bar.len() {
-            self.rpm_bar[i] = false;
+        for (i, lit) in self.rpm_bar.iter_mut().enumerate() {
+            *lit = i < rpm_percentage;
         }
     }
 
-    fn init_statics(&mut self, statics: &serde_json::Value) {
-        self.rpm_max = match statics["maxRpm"].as_u64() {
-            Some(num) => num,
-            None      => 0 as u64
-        }
+    fn init_statics(&mut self, statics: &StaticData) {
+        self.rpm_max = statics.max_rpm;
     }
-} 
 
+    fn height(&self) -> u16 {
+        5
+    }
+}
+
+#[derive(Default)]
 pub struct TyreTemps {
-    coords: Bounds,
-    tyres: [f64; 4] // Tyres going clockwise from front left (0) to rear left (3)
+    tyres: Wheels<f64>
 }
 
 impl TyreTemps {
-    pub fn new(x: u16, y: u16) -> TyreTemps {
-        return TyreTemps {
-            coords: Bounds::new(x, y, 0, 0),
-            tyres: [0 as f64; 4]
+    pub fn new() -> TyreTemps {
+        TyreTemps {
+            tyres: [0.0; 4]
         }
     }
-
-    fn print_tyre_with_offset(&self, x_offset: u16, y_offset: u16, tyre_index: usize) {
-        let text_color: &str;
-        if self.tyres[tyre_index] < TYRE_NUM_COLD {
-            text_color = TEXT_COLOR_COLD;
-        }
-        else if self.tyres[tyre_index] < TYRE_NUM_OPTIMAL {
-            text_color = TEXT_COLOR_OPTIMAL;
-        }
-        else if self.tyres[tyre_index] < TYRE_NUM_WARNING {
-            text_color = TEXT_COLOR_WARNING;
-        }
-        else {
-            text_color = TEXT_COLOR_TOO_HOT;
-        }
-
-        println!("{}{}{:.0}{}", 
-                 cursor::MoveTo(self.coords.start_x + x_offset,
-                                self.coords.start_y + y_offset),
-                 text_color,
-                 self.tyres[tyre_index],
-                 COLOR_RESET);
-    }
 }
 
 impl TUIBlock for TyreTemps {
-    fn display(&self) {
-        print!("{}Tyres:",
-               cursor::MoveTo(self.coords.start_x, self.coords.start_y));
-
-        // TODO: Can we iterate over the tyres somehow?
-        //       if we do, the tyres will have to become
-        //       their own structs and know their offsets
-        self.print_tyre_with_offset(2, 1, 0);
-        self.print_tyre_with_offset(8, 1, 1);
-        self.print_tyre_with_offset(8, 3, 2);
-        self.print_tyre_with_offset(2, 3, 3);
-    }
-
-    fn update(&mut self, physics: &serde_json::Value, _graphics: &serde_json::Value) {
-        let temps = match physics["tyreTemp"].as_array() {
-            Some(arr) => arr,
-            None => { return; }
-        };
+    fn display(&self, frame: &mut Frame<TermBackend>, area: Rect) {
+        let wheels = self.tyres.map(|temp| {
+            let style = temperature_style(temp, TYRE_NUM_COLD, TYRE_NUM_OPTIMAL, TYRE_NUM_WARNING);
+            Span::styled(format!("{:<4.0}", temp), style)
+        });
 
-        for i in 0..self.tyres.len() {
-            self.tyres[i] = temps[i].as_f64().unwrap();
-        }
+        frame.render_widget(Paragraph::new(wheel_grid(wheels)).block(bordered_block("Tyres")), area);
     }
 
-    fn init_statics(&mut self, _statics: &serde_json::Value) {
-        return;
+    fn update(&mut self, physics: &PhysicsData, _graphics: &GraphicsData) {
+        self.tyres = physics.tyre_temp;
+    }
+
+    fn init_statics(&mut self, _statics: &StaticData) { }
+
+    fn height(&self) -> u16 {
+        5
     }
 }
 
+#[derive(Default)]
 pub struct TyrePressures {
-    coords: Bounds,
-    tyres: [f64; 4] // Tyres going clockwise from front left (0) to rear left (3)
+    tyres: Wheels<f64>
 }
 
 impl TyrePressures {
-    pub fn new(x: u16, y: u16) -> TyrePressures {
-        return TyrePressures {
-            coords: Bounds::new(x, y, 0, 0),
-            tyres: [0 as f64; 4]
+    pub fn new() -> TyrePressures {
+        TyrePressures {
+            tyres: [0.0; 4]
         }
     }
-
-    fn print_tyre_with_offset(&self, x_offset: u16, y_offset: u16, tyre_index: usize) {
-        println!("{}{:.2}", 
-                 cursor::MoveTo(self.coords.start_x + x_offset,
-                                self.coords.start_y + y_offset),
-                 self.tyres[tyre_index]);
-    }
 }
 
 impl TUIBlock for TyrePressures {
-    fn display(&self) {
-        print!("{}Tyre Pressures (psi):",
-               cursor::MoveTo(self.coords.start_x, self.coords.start_y));
-
-        // TODO: Can we iterate over the tyres somehow?
-        //       if we do, the tyres will have to become
-        //       their own structs and know their offsets
-        self.print_tyre_with_offset(2, 1, 0);
-        self.print_tyre_with_offset(8, 1, 1);
-        self.print_tyre_with_offset(8, 3, 2);
-        self.print_tyre_with_offset(2, 3, 3);
-    }
-
-    fn update(&mut self, physics: &serde_json::Value, _graphics: &serde_json::Value) {
-        let pressures = match physics["wheelsPressure"].as_array() {
-            Some(arr) => arr,
-            None => { return; }
-        };
+    fn display(&self, frame: &mut Frame<TermBackend>, area: Rect) {
+        let wheels = self.tyres.map(|pressure| Span::raw(format!("{:<5.2}", pressure)));
 
-        for i in 0..self.tyres.len() {
-            self.tyres[i] = pressures[i].as_f64().unwrap();
-        }
+        frame.render_widget(Paragraph::new(wheel_grid(wheels))
+                                .block(bordered_block("Tyre Pressures (psi)")), area);
     }
 
-    fn init_statics(&mut self, _statics: &serde_json::Value) {
-        return;
+    fn update(&mut self, physics: &PhysicsData, _graphics: &GraphicsData) {
+        self.tyres = physics.wheels_pressure;
+    }
+
+    fn init_statics(&mut self, _statics: &StaticData) { }
+
+    fn height(&self) -> u16 {
+        5
     }
 }
 
+#[derive(Default)]
 pub struct LapTimes {
-    coords: Bounds,
     time_cur: String,
     time_last: String,
     time_best: String
 }
 
 impl LapTimes {
-    pub fn new(x: u16, y: u16) -> LapTimes {
-        return LapTimes {
-            coords: Bounds::new(x, y, 0, 0),
+    pub fn new() -> LapTimes {
+        LapTimes {
             time_cur: String::new(),
             time_last: String::new(),
             time_best: String::new()
@@ -285,170 +258,1123 @@ impl LapTimes {
 }
 
 impl TUIBlock for LapTimes {
-    fn update(&mut self, _physics: &serde_json::Value, graphics: &serde_json::Value) {
-        let time_cur = graphics["currentTime"].as_str();
-        let time_last = graphics["lastTime"].as_str();
-        let time_best = graphics["bestTime"].as_str();
+    fn update(&mut self, _physics: &PhysicsData, graphics: &GraphicsData) {
+        self.time_cur.clone_from(&graphics.current_time);
 
-        match time_cur {
-            Some(s) => self.time_cur = s.to_string(),
-            None => ()
+        if self.time_last != graphics.last_time {
+            self.time_last.clone_from(&graphics.last_time);
         }
 
-        match time_last {
-            Some(s)=> {
-                if self.time_last != s.to_string() {
-                    self.time_last = s.to_string();
-                }
-            }
-            None => ()
-        }
-
-        match time_best {
-            Some(s)=> {
-                if self.time_best != s.to_string() {
-                    self.time_best = s.to_string();
-                }
-            }
-            None => ()
+        if self.time_best != graphics.best_time {
+            self.time_best.clone_from(&graphics.best_time);
         }
     }
 
-    fn display(&self) {
-        println!("{}Lap Times", cursor::MoveTo(self.coords.start_x, self.coords.start_y));
-        println!("{}Current Lap: {}",
-                 cursor::MoveTo(self.coords.start_x + 2, self.coords.start_y + 1),
-                 self.time_cur);
-        println!("{}Last Lap:    {}",
-                 cursor::MoveTo(self.coords.start_x + 2, self.coords.start_y + 2),
-                 self.time_last);
-        println!("{}Best Lap:    {}",
-                 cursor::MoveTo(self.coords.start_x + 2, self.coords.start_y + 3),
-                 self.time_best);
+    fn display(&self, frame: &mut Frame<TermBackend>, area: Rect) {
+        let text = vec![
+            Spans::from(format!("Current Lap: {}", self.time_cur)),
+            Spans::from(format!("Last Lap:    {}", self.time_last)),
+            Spans::from(format!("Best Lap:    {}", self.time_best))
+        ];
+
+        frame.render_widget(Paragraph::new(text).block(bordered_block("Lap Times")), area);
     }
 
-    fn init_statics(&mut self, _statics: &serde_json::Value) {
-        return;
+    fn init_statics(&mut self, _statics: &StaticData) { }
+
+    fn height(&self) -> u16 {
+        5
     }
 }
 
+#[derive(Default)]
 pub struct Thermometer {
-    coords: Bounds,
     temp_track: f64,
     temp_air: f64
 }
 
 impl Thermometer {
-    pub fn new(x: u16, y: u16) -> Thermometer {
-        return Thermometer {
-            coords: Bounds::new(x, y, 0, 0),
-            temp_track: 0 as f64,
-            temp_air: 0 as f64
+    pub fn new() -> Thermometer {
+        Thermometer {
+            temp_track: 0.0,
+            temp_air: 0.0
         }
     }
 }
 
 impl TUIBlock for Thermometer {
-    fn update(&mut self, physics: &serde_json::Value, _graphics: &serde_json::Value) {
-        self.temp_track = match physics["roadTemp"].as_f64() {
-            Some(val) => val,
-            None => self.temp_track
+    fn update(&mut self, physics: &PhysicsData, _graphics: &GraphicsData) {
+        self.temp_track = physics.road_temp;
+        self.temp_air = physics.air_temp;
+    }
+
+    fn display(&self, frame: &mut Frame<TermBackend>, area: Rect) {
+        let text = vec![
+            Spans::from(format!("Track Temp: {:.1}", self.temp_track)),
+            Spans::from(format!("Air Temp:   {:.1}", self.temp_air))
+        ];
+
+        frame.render_widget(Paragraph::new(text).block(bordered_block("Thermometer")), area);
+    }
+
+    fn init_statics(&mut self, _statics: &StaticData) { }
+
+    fn height(&self) -> u16 {
+        4
+    }
+}
+
+#[derive(Default)]
+pub struct BrakeTemps {
+    brakes: Wheels<f64>
+}
+
+impl BrakeTemps {
+    pub fn new() -> BrakeTemps {
+        BrakeTemps {
+            brakes: [0.0; 4]
+        }
+    }
+}
+
+impl TUIBlock for BrakeTemps {
+    fn display(&self, frame: &mut Frame<TermBackend>, area: Rect) {
+        let wheels = [0, 1, 2, 3].map(|brake_index| {
+            let temp = self.brakes[brake_index];
+
+            // Rears run cooler, so they are judged against the same scale with an offset
+            let cur_temp = if brake_index > 1 { temp + 200.0 } else { temp };
+            let style = temperature_style(cur_temp, BRAKE_NUM_COLD, BRAKE_NUM_OPTIMAL, BRAKE_NUM_WARNING);
+
+            Span::styled(format!("{:<4.0}", temp), style)
+        });
+
+        frame.render_widget(Paragraph::new(wheel_grid(wheels)).block(bordered_block("Brake temps")), area);
+    }
+
+    fn update(&mut self, physics: &PhysicsData, _graphics: &GraphicsData) {
+        self.brakes = physics.brake_temp;
+    }
+
+    fn init_statics(&mut self, _statics: &StaticData) { }
+
+    fn height(&self) -> u16 {
+        5
+    }
+}
+
+pub struct FuelCalculator {
+    safety_margin_laps: f64,
+    max_fuel: f64,
+    fuel: f64,
+    fuel_x_lap: f64,
+    session_time_left_ms: f64,
+    race_laps_left: Option<u32>,
+    last_completed_laps: Option<u32>,
+    fuel_at_crossing: Option<f64>,
+    lap_usage: VecDeque<f64>,
+    lap_times_ms: VecDeque<i64>
+}
+
+impl FuelCalculator {
+    pub fn new() -> FuelCalculator {
+        FuelCalculator {
+            safety_margin_laps: config::load_fuel_safety_margin(),
+            max_fuel: 0.0,
+            fuel: 0.0,
+            fuel_x_lap: 0.0,
+            session_time_left_ms: 0.0,
+            race_laps_left: None,
+            last_completed_laps: None,
+            fuel_at_crossing: None,
+            lap_usage: VecDeque::new(),
+            lap_times_ms: VecDeque::new()
+        }
+    }
+
+    // Until a full lap has been measured, fall back on ACC's own estimate
+    fn average_usage(&self) -> f64 {
+        if self.lap_usage.is_empty() {
+            return self.fuel_x_lap;
+        }
+
+        self.lap_usage.iter().sum::<f64>() / self.lap_usage.len() as f64
+    }
+
+    fn average_lap_time_ms(&self) -> Option<f64> {
+        if self.lap_times_ms.is_empty() {
+            return None;
+        }
+
+        Some(self.lap_times_ms.iter().sum::<i64>() as f64 / self.lap_times_ms.len() as f64)
+    }
+
+    /// Laps still to run: counted down in a lap race, otherwise estimated
+    /// from the time left. Rounding up counts the lap that is running as the
+    /// clock runs out.
+    fn laps_to_finish(&self) -> Option<f64> {
+        if let Some(laps) = self.race_laps_left {
+            return Some(laps as f64);
+        }
+
+        let lap_time_ms = self.average_lap_time_ms()?;
+        if self.session_time_left_ms <= 0.0 {
+            return Some(0.0);
+        }
+
+        Some((self.session_time_left_ms / lap_time_ms).ceil())
+    }
+
+    /// Fuel to add to finish with the safety margin in hand, but never more
+    /// than there is room for in the tank
+    fn fuel_to_add(&self) -> Option<f64> {
+        let usage = self.average_usage();
+        if usage <= 0.0 {
+            return None;
+        }
+
+        let laps = self.laps_to_finish()?;
+        let to_add = ((laps + self.safety_margin_laps) * usage - self.fuel).max(0.0);
+
+        match self.max_fuel > 0.0 {
+            true => Some(to_add.min((self.max_fuel - self.fuel).max(0.0))),
+            false => Some(to_add)
+        }
+    }
+
+    fn on_lap_completed(&mut self, graphics: &GraphicsData) {
+        // Fuel going up means the car was refuelled, so that lap says nothing
+        if let Some(start_fuel) = self.fuel_at_crossing {
+            let used = start_fuel - self.fuel;
+            if used > 0.0 {
+                push_bounded(&mut self.lap_usage, used, FUEL_AVERAGE_LAPS);
+            }
+        }
+
+        if graphics.i_last_time > 0 {
+            push_bounded(&mut self.lap_times_ms, graphics.i_last_time, FUEL_AVERAGE_LAPS);
+        }
+
+        self.fuel_at_crossing = Some(self.fuel);
+    }
+
+    fn reset(&mut self) {
+        self.fuel_at_crossing = None;
+        self.lap_usage.clear();
+        self.lap_times_ms.clear();
+    }
+}
+
+impl Default for FuelCalculator {
+    fn default() -> FuelCalculator {
+        FuelCalculator::new()
+    }
+}
+
+impl TUIBlock for FuelCalculator {
+    fn update(&mut self, physics: &PhysicsData, graphics: &GraphicsData) {
+        self.fuel = physics.fuel;
+        self.fuel_x_lap = graphics.fuel_x_lap;
+        self.session_time_left_ms = graphics.session_time_left;
+        self.race_laps_left = match graphics.number_of_laps {
+            0 => None,
+            laps => Some(laps.saturating_sub(graphics.completed_laps))
         };
 
-        self.temp_air = match physics["airTemp"].as_f64() {
-            Some(val) => val,
-            None => self.temp_air
+        match self.last_completed_laps {
+            // Fewer laps than before means a new session has started
+            Some(last) if graphics.completed_laps < last => self.reset(),
+            // The first crossing we see only sets the baseline, as the lap
+            // before it was only partly observed
+            Some(last) if graphics.completed_laps == last + 1 => self.on_lap_completed(graphics),
+            // Several laps at once, after seeking a replay or a gap in the
+            // data, don't say what any one of them used, so only start again
+            Some(last) if graphics.completed_laps > last => self.fuel_at_crossing = Some(self.fuel),
+            _ => { }
+        }
+
+        self.last_completed_laps = Some(graphics.completed_laps);
+    }
+
+    fn display(&self, frame: &mut Frame<TermBackend>, area: Rect) {
+        let usage = self.average_usage();
+
+        let laps_left = match usage > 0.0 {
+            true => format!("{:.1}", self.fuel / usage),
+            false => String::from("-")
+        };
+
+        let (laps_to_go, to_finish, to_add) = match (self.laps_to_finish(), self.fuel_to_add()) {
+            (Some(laps), Some(to_add)) => {
+                (format!("{:.0} laps", laps),
+                 format!("{:.1} L", laps * usage),
+                 format!("{:.1} L (+{} lap)", to_add, self.safety_margin_laps))
+            },
+            _ => (String::from("-"), String::from("-"), String::from("-"))
         };
+
+        let text = vec![
+            Spans::from(format!("Fuel:      {:.1} L", self.fuel)),
+            Spans::from(format!("Per lap:   {:.2} L ({} laps)", usage, self.lap_usage.len())),
+            Spans::from(format!("Laps left: {}", laps_left)),
+            Spans::from(format!("To go:     {}", laps_to_go)),
+            Spans::from(format!("To finish: {}", to_finish)),
+            Spans::from(format!("Add:       {}", to_add))
+        ];
+
+        frame.render_widget(Paragraph::new(text).block(bordered_block("Fuel")), area);
     }
 
-    fn display(&self) {
-        println!("{}Thermometer", cursor::MoveTo(self.coords.start_x, self.coords.start_y));
-        println!("{}Track Temp: {:.1}",
-                 cursor::MoveTo(self.coords.start_x + 2, self.coords.start_y + 1),
-                 self.temp_track);
-        println!("{}Air Temp:   {:.1}",
-                 cursor::MoveTo(self.coords.start_x + 2, self.coords.start_y + 2),
-                 self.temp_air);
+    fn init_statics(&mut self, statics: &StaticData) {
+        self.max_fuel = statics.max_fuel;
     }
 
-    fn init_statics(&mut self, _statics: &serde_json::Value) {
-        return;
+    fn height(&self) -> u16 {
+        8
     }
 }
 
-pub struct BrakeTemps {
-    coords: Bounds,
-    brakes: [f64; 4]
+fn push_bounded<T>(values: &mut VecDeque<T>, value: T, max_len: usize) {
+    values.push_back(value);
+    while values.len() > max_len {
+        values.pop_front();
+    }
 }
 
-impl BrakeTemps {
-    pub fn new(x: u16, y: u16) -> BrakeTemps {
-        return BrakeTemps {
-            coords: Bounds::new(x, y, 0, 0),
-            brakes: [0 as f64; 4]
+/// One completed lap in the lap history
+struct LapRecord {
+    lap: u32,
+    time_ms: i64,
+    sectors: Vec<Option<i64>>,
+    valid: bool,
+    fuel_used: f64,
+    tyre_temp: f64,
+    tyre_pressure: f64,
+    track_temp: f64
+}
+
+/// Running totals for the lap in progress, averaged when it completes
+struct LapInProgress {
+    sectors: Vec<Option<i64>>,
+    sector_index: u32,
+    valid: bool,
+    fuel_start: f64,
+    samples: u32,
+    tyre_temp_sum: f64,
+    tyre_pressure_sum: f64,
+    track_temp_sum: f64
+}
+
+impl LapInProgress {
+    fn new(sector_count: usize, physics: &PhysicsData, graphics: &GraphicsData) -> LapInProgress {
+        LapInProgress {
+            sectors: vec![None; sector_count],
+            sector_index: graphics.current_sector_index,
+            valid: true,
+            fuel_start: physics.fuel,
+            samples: 0,
+            tyre_temp_sum: 0.0,
+            tyre_pressure_sum: 0.0,
+            track_temp_sum: 0.0
         }
     }
 
-    fn print_temp_with_offset(&self, x_offset: u16, y_offset: u16, brake_index: usize) {
-        let text_color: &str;
-        let cur_temp: f64;
+    fn sample(&mut self, physics: &PhysicsData, graphics: &GraphicsData) {
+        // ACC reports each sector's time once the car has left it
+        if graphics.current_sector_index == self.sector_index + 1 {
+            if let Some(split) = self.sectors.get_mut(self.sector_index as usize) {
+                *split = Some(graphics.last_sector_time);
+            }
+        }
+        self.sector_index = graphics.current_sector_index;
+
+        self.valid &= graphics.is_valid_lap;
+        self.samples += 1;
+        self.tyre_temp_sum += physics.tyre_temp.iter().sum::<f64>() / 4.0;
+        self.tyre_pressure_sum += physics.wheels_pressure.iter().sum::<f64>() / 4.0;
+        self.track_temp_sum += physics.road_temp;
+    }
 
-        if brake_index > 1 {
-            cur_temp = self.brakes[brake_index] + 200.0;
+    fn finish(mut self, lap: u32, time_ms: i64, fuel_end: f64) -> LapRecord {
+        // The final sector is whatever is left of the lap time, as long as
+        // every earlier sector was seen
+        let (last, earlier) = self.sectors.split_last_mut().unwrap();
+        if earlier.iter().all(|split| split.is_some()) {
+            let earlier_ms: i64 = earlier.iter().flatten().sum();
+            *last = Some(time_ms - earlier_ms);
         }
-        else {
-            cur_temp = self.brakes[brake_index];
+
+        let samples = std::cmp::max(self.samples, 1) as f64;
+
+        LapRecord {
+            lap,
+            time_ms,
+            sectors: self.sectors,
+            valid: self.valid,
+            fuel_used: (self.fuel_start - fuel_end).max(0.0),
+            tyre_temp: self.tyre_temp_sum / samples,
+            tyre_pressure: self.tyre_pressure_sum / samples,
+            track_temp: self.track_temp_sum / samples
         }
+    }
+}
+
+pub struct LapHistory {
+    laps: Vec<LapRecord>,
+    current: Option<LapInProgress>,
+    last_completed_laps: Option<u32>,
+    sector_count: usize,
+    scroll_offset: usize
+}
 
-        if cur_temp < BRAKE_NUM_COLD {
-            text_color = TEXT_COLOR_COLD;
+impl LapHistory {
+    pub fn new() -> LapHistory {
+        LapHistory {
+            laps: Vec::new(),
+            current: None,
+            last_completed_laps: None,
+            sector_count: DEFAULT_SECTOR_COUNT,
+            scroll_offset: 0
         }
-        else if cur_temp < BRAKE_NUM_OPTIMAL {
-            text_color = TEXT_COLOR_OPTIMAL;
+    }
+
+    /// Best time for each sector over the valid laps
+    fn best_sectors(&self) -> Vec<Option<i64>> {
+        (0..self.sector_count)
+            .map(|index| self.laps.iter()
+                .filter(|lap| lap.valid)
+                .filter_map(|lap| lap.sectors.get(index).copied().flatten())
+                .min())
+            .collect()
+    }
+
+    fn best_lap_ms(&self) -> Option<i64> {
+        self.laps.iter().filter(|lap| lap.valid).map(|lap| lap.time_ms).min()
+    }
+
+    fn lap_row(&self, lap: &LapRecord, best_sectors: &[Option<i64>], best_lap_ms: Option<i64>) -> Row<'_> {
+        let personal_best = Style::default().fg(Color::Magenta).add_modifier(Modifier::BOLD);
+
+        let time_style = match is_personal_best(lap, lap.time_ms, best_lap_ms) {
+            true => personal_best,
+            false => Style::default()
+        };
+
+        let mut cells = vec![
+            Cell::from(lap.lap.to_string()),
+            Cell::from(format_lap_time(lap.time_ms)).style(time_style)
+        ];
+
+        for (split, best) in lap.sectors.iter().zip(best_sectors) {
+            let cell = match split {
+                Some(ms) if is_personal_best(lap, *ms, *best) => Cell::from(format_lap_time(*ms)).style(personal_best),
+                Some(ms) => Cell::from(format_lap_time(*ms)),
+                None => Cell::from("-")
+            };
+            cells.push(cell);
         }
-        else if cur_temp < BRAKE_NUM_WARNING {
-            text_color = TEXT_COLOR_WARNING;
+
+        cells.push(Cell::from(format!("{:.1}", lap.fuel_used)));
+        cells.push(Cell::from(format!("{:.0}", lap.tyre_temp)));
+        cells.push(Cell::from(format!("{:.1}", lap.tyre_pressure)));
+        cells.push(Cell::from(format!("{:.0}", lap.track_temp)));
+
+        let row = Row::new(cells);
+        match lap.valid {
+            true => row,
+            false => row.style(Style::default().fg(Color::DarkGray))
         }
-        else {
-            text_color = TEXT_COLOR_TOO_HOT;
+    }
+}
+
+// Only valid laps can set a personal best
+fn is_personal_best(lap: &LapRecord, time_ms: i64, best_ms: Option<i64>) -> bool {
+    lap.valid && Some(time_ms) == best_ms
+}
+
+impl Default for LapHistory {
+    fn default() -> LapHistory {
+        LapHistory::new()
+    }
+}
+
+impl TUIBlock for LapHistory {
+    fn update(&mut self, physics: &PhysicsData, graphics: &GraphicsData) {
+        match self.last_completed_laps {
+            // Fewer laps than before means a new session has started
+            Some(last) if graphics.completed_laps < last => {
+                self.laps.clear();
+                self.scroll_offset = 0;
+                self.current = Some(LapInProgress::new(self.sector_count, physics, graphics));
+            },
+            Some(last) if graphics.completed_laps > last => {
+                // The lap we joined part way through is never recorded, and
+                // nor is one that ran into others after seeking a replay or
+                // a gap in the data, as it would mix several laps together
+                let finished = self.current.take().filter(|_| graphics.completed_laps == last + 1);
+                if let Some(current) = finished {
+                    self.laps.push(current.finish(graphics.completed_laps, graphics.i_last_time, physics.fuel));
+
+                    // Keep the view still if the engineer has scrolled back
+                    if self.scroll_offset > 0 {
+                        self.scroll_offset += 1;
+                    }
+                }
+                self.current = Some(LapInProgress::new(self.sector_count, physics, graphics));
+            },
+            _ => { }
+        }
+
+        if let Some(current) = self.current.as_mut() {
+            current.sample(physics, graphics);
+        }
+
+        self.last_completed_laps = Some(graphics.completed_laps);
+    }
+
+    fn display(&self, frame: &mut Frame<TermBackend>, area: Rect) {
+        let best_sectors = self.best_sectors();
+        let best_lap_ms = self.best_lap_ms();
+
+        // Newest lap first, two rows go to the border and one to the header
+        let visible = area.height.saturating_sub(3) as usize;
+        let rows: Vec<Row> = self.laps.iter().rev()
+            .skip(self.scroll_offset)
+            .take(visible)
+            .map(|lap| self.lap_row(lap, &best_sectors, best_lap_ms))
+            .collect();
+
+        let mut header = vec![String::from("Lap"), String::from("Time")];
+        header.extend((1..=self.sector_count).map(|sector| format!("S{}", sector)));
+        header.extend(["Fuel", "Tyre", "PSI", "Trk"].iter().map(|title| title.to_string()));
+
+        let mut widths = vec![Constraint::Length(3), Constraint::Length(8)];
+        widths.extend(vec![Constraint::Length(6); self.sector_count]);
+        widths.extend(vec![Constraint::Length(4); 4]);
+
+        let title = match self.scroll_offset {
+            0 => String::from("Lap History"),
+            offset => format!("Lap History (-{})", offset)
+        };
+
+        let table = Table::new(rows)
+            .header(Row::new(header).style(Style::default().add_modifier(Modifier::BOLD)))
+            .widths(&widths)
+            .block(bordered_block(&title));
+
+        frame.render_widget(table, area);
+    }
+
+    fn init_statics(&mut self, statics: &StaticData) {
+        if statics.sector_count > 0 {
+            self.sector_count = statics.sector_count as usize;
         }
+    }
+
+    fn height(&self) -> u16 {
+        LAP_HISTORY_HEIGHT
+    }
 
-        println!("{}{}{:.0}{}", 
-                 cursor::MoveTo(self.coords.start_x + x_offset,
-                                self.coords.start_y + y_offset),
-                 text_color,
-                 self.brakes[brake_index],
-                 COLOR_RESET);
+    fn scroll(&mut self, lines: i32) {
+        let max_offset = self.laps.len().saturating_sub(1) as i64;
+        self.scroll_offset = (self.scroll_offset as i64 + lines as i64).clamp(0, max_offset) as usize;
     }
 }
 
-impl TUIBlock for BrakeTemps {
-    fn display(&self) {
-        print!("{}Brake temps:",
-               cursor::MoveTo(self.coords.start_x, self.coords.start_y));
-
-        // TODO: Can we iterate over the wheels somehow?
-        //       if we do, they may have to become
-        //       their own structs and know their offsets
-        self.print_temp_with_offset(2, 1, 0);
-        self.print_temp_with_offset(8, 1, 1);
-        self.print_temp_with_offset(8, 3, 2);
-        self.print_temp_with_offset(2, 3, 3);
-    }
-
-    fn update(&mut self, physics: &serde_json::Value, _graphics: &serde_json::Value) {
-        let temps = match physics["brakeTemp"].as_array() {
-            Some(arr) => arr,
-            None => { return; }
+// "1:45.123" for laps, "35.123" for anything under a minute
+fn format_lap_time(ms: i64) -> String {
+    let ms = ms.max(0);
+    let (mins, secs, millis) = (ms / 60000, (ms / 1000) % 60, ms % 1000);
+
+    match mins {
+        0 => format!("{}.{:03}", secs, millis),
+        _ => format!("{}:{:02}.{:03}", mins, secs, millis)
+    }
+}
+
+#[derive(Default)]
+pub struct LinkQuality {
+    stats: Option<LinkStats>
+}
+
+impl LinkQuality {
+    pub fn new() -> LinkQuality {
+        LinkQuality {
+            stats: None
+        }
+    }
+}
+
+impl TUIBlock for LinkQuality {
+    fn update(&mut self, _physics: &PhysicsData, _graphics: &GraphicsData) { }
+
+    fn display(&self, frame: &mut Frame<TermBackend>, area: Rect) {
+        let text = match &self.stats {
+            Some(stats) => {
+                let loss_style = match stats.loss_percent() {
+                    loss if loss >= 5.0 => bold_color(Color::Red),
+                    loss if loss >= 1.0 => bold_color(Color::Yellow),
+                    _ => Style::default()
+                };
+
+                vec![
+                    Spans::from(format!("Rate:   {:.0} pkt/s", stats.packets_per_second)),
+                    Spans::from(format!("Bytes:  {}", format_byte_rates(stats))),
+                    Spans::from(format!("Jitter: {:.1} ms", stats.jitter_ms)),
+                    Spans::from(vec![
+                        Span::raw("Lost:   "),
+                        Span::styled(format!("{} ({:.1}%)", stats.lost, stats.loss_percent()), loss_style)
+                    ]),
+                    Spans::from(format!("Late:   {}", stats.out_of_order)),
+                    Spans::from(format!("Dupes:  {}", stats.duplicates)),
+                    Spans::from(format!("Bad:    {}", stats.dropped())),
+                    Spans::from(format!("RTT:    {}", format_optional_ms(stats.rtt_ms))),
+                    Spans::from(format!("Offset: {}", format_optional_ms(stats.clock_offset_ms)))
+                ]
+            },
+            None => vec![Spans::from("No live connection")]
+        };
+
+        frame.render_widget(Paragraph::new(text).block(bordered_block("Link Quality")), area);
+    }
+
+    fn init_statics(&mut self, _statics: &StaticData) { }
+
+    fn height(&self) -> u16 {
+        11
+    }
+
+    fn update_link(&mut self, stats: &LinkStats) {
+        self.stats = Some(stats.clone());
+    }
+}
+
+// One figure per encoding seen recently, normally just the one
+fn format_byte_rates(stats: &LinkStats) -> String {
+    if stats.bytes_per_second.is_empty() {
+        return String::from("-");
+    }
+
+    stats.bytes_per_second.iter()
+        .map(|(encoding, rate)| format!("{:.1} kB/s {}", rate / 1000.0, encoding))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// Round trip figures stay blank until a server has answered a ping
+fn format_optional_ms(ms: Option<f64>) -> String {
+    match ms {
+        // Adding zero turns a rounded -0 into 0
+        Some(ms) => format!("{:.0} ms", ms.round() + 0.0),
+        None => String::from("-")
+    }
+}
+
+// A channel named in a custom block, e.g. "tyreTemp[0]" or "carCoordinates[0][2]"
+struct ChannelKey {
+    key: String,
+    name: String,
+    indices: Vec<usize>
+}
+
+impl ChannelKey {
+    fn parse(key: &str) -> Result<ChannelKey, String> {
+        let invalid = || format!("Invalid key \"{}\"", key);
+
+        let name_end = key.find('[').unwrap_or(key.len());
+        let name = key[..name_end].trim();
+        if name.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut indices: Vec<usize> = Vec::new();
+        let mut rest = &key[name_end..];
+        while !rest.is_empty() {
+            let close = rest.find(']').ok_or_else(invalid)?;
+            if !rest.starts_with('[') {
+                return Err(invalid());
+            }
+            indices.push(rest[1..close].trim().parse().map_err(|_| invalid())?);
+            rest = &rest[close + 1..];
+        }
+
+        Ok(ChannelKey { key: String::from(key), name: String::from(name), indices })
+    }
+
+    // Takes the channel named by the key and indexes into it
+    fn resolve(&self, channel: Option<serde_json::Value>) -> Option<serde_json::Value> {
+        let mut value = channel?;
+        for index in self.indices.iter() {
+            value = value.get_mut(*index)?.take();
+        }
+
+        Some(value)
+    }
+}
+
+// The subset of Rust's format spec custom blocks support: [align][width][.precision]
+#[derive(Default)]
+struct FormatSpec {
+    align: Option<char>,
+    width: usize,
+    precision: Option<usize>
+}
+
+impl FormatSpec {
+    fn parse(spec: &str) -> Result<FormatSpec, String> {
+        let invalid = || format!("Invalid format spec \":{}\"", spec);
+        let mut format_spec = FormatSpec::default();
+        let mut rest = spec;
+
+        if let Some(align) = rest.chars().next().filter(|c| ['<', '>', '^'].contains(c)) {
+            format_spec.align = Some(align);
+            rest = &rest[1..];
+        }
+
+        let (width, precision) = match rest.find('.') {
+            Some(dot) => (&rest[..dot], Some(&rest[dot + 1..])),
+            None => (rest, None)
+        };
+
+        if !width.is_empty() {
+            format_spec.width = width.parse().map_err(|_| invalid())?;
+        }
+
+        if let Some(precision) = precision {
+            format_spec.precision = Some(precision.parse().map_err(|_| invalid())?);
+        }
+
+        Ok(format_spec)
+    }
+
+    fn apply(&self, value: Option<&serde_json::Value>) -> String {
+        let (text, is_number) = match value {
+            Some(serde_json::Value::Number(num)) => match (self.precision, num.as_f64()) {
+                (Some(precision), Some(float)) => (format!("{:.*}", precision, float), true),
+                _ => (num.to_string(), true)
+            },
+            Some(serde_json::Value::String(text)) => (text.clone(), false),
+            Some(serde_json::Value::Null) | None => (String::from("-"), false),
+            Some(other) => (other.to_string(), false)
+        };
+
+        // Like Rust, numbers default to the right and everything else to the left
+        let align = self.align.unwrap_or(if is_number { '>' } else { '<' });
+        match align {
+            '>' => format!("{:>1$}", text, self.width),
+            '^' => format!("{:^1$}", text, self.width),
+            _   => format!("{:<1$}", text, self.width)
+        }
+    }
+}
+
+enum TemplatePart {
+    Text(String),
+    Field { key: String, spec: FormatSpec }
+}
+
+// Splits a format_str into literal text and {key:spec} fields, with {{ and }}
+// standing for literal braces
+fn parse_template(template: &str) -> Result<Vec<TemplatePart>, String> {
+    let mut parts: Vec<TemplatePart> = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => { chars.next(); text.push('{'); },
+            '}' if chars.peek() == Some(&'}') => { chars.next(); text.push('}'); },
+            '{' => {
+                let mut field = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => field.push(c),
+                        None => { return Err(format!("Unclosed \"{{{}\"", field)); }
+                    }
+                }
+
+                let (key, spec) = match field.find(':') {
+                    Some(colon) => (&field[..colon], FormatSpec::parse(&field[colon + 1..])?),
+                    None => (field.as_str(), FormatSpec::default())
+                };
+
+                if !text.is_empty() {
+                    parts.push(TemplatePart::Text(std::mem::take(&mut text)));
+                }
+                parts.push(TemplatePart::Field { key: String::from(key.trim()), spec });
+            },
+            _ => text.push(c)
+        }
+    }
+
+    if !text.is_empty() {
+        parts.push(TemplatePart::Text(text));
+    }
+
+    Ok(parts)
+}
+
+/// A block defined entirely in options.yaml, showing whichever physics and
+/// graphics channels it lists through its format_str template
+pub struct CustomBlock {
+    title: String,
+    physics_keys: Vec<ChannelKey>,
+    graphics_keys: Vec<ChannelKey>,
+    template: Vec<TemplatePart>,
+    line_count: u16,
+    values: HashMap<String, serde_json::Value>
+}
+
+impl CustomBlock {
+    pub fn new(config: &CustomBlockConfig) -> Result<CustomBlock, String> {
+        let in_block = |e: String| format!("{} in custom block \"{}\"", e, config.name);
+
+        let parse_keys = |keys: &Vec<String>| -> Result<Vec<ChannelKey>, String> {
+            keys.iter().map(|key| ChannelKey::parse(key).map_err(in_block)).collect()
         };
 
-        for i in 0..self.brakes.len() {
-            self.brakes[i] = temps[i].as_f64().unwrap();
+        let physics_keys = parse_keys(&config.physics_keys)?;
+        let graphics_keys = parse_keys(&config.graphics_keys)?;
+        let format_str = config.format_str.trim_end();
+        let template = parse_template(format_str).map_err(in_block)?;
+
+        for part in template.iter() {
+            if let TemplatePart::Field { key, .. } = part {
+                let is_listed = physics_keys.iter().chain(graphics_keys.iter())
+                    .any(|channel| channel.key == *key);
+
+                if !is_listed {
+                    return Err(in_block(format!("{{{}}} is not in physics_keys or graphics_keys", key)));
+                }
+            }
         }
+
+        Ok(CustomBlock {
+            title: config.title.clone(),
+            physics_keys,
+            graphics_keys,
+            template,
+            line_count: format_str.lines().count().max(1) as u16,
+            values: HashMap::new()
+        })
     }
 
-    fn init_statics(&mut self, _statics: &serde_json::Value) {
-        return;
+    fn render_template(&self) -> String {
+        let mut rendered = String::new();
+
+        for part in self.template.iter() {
+            match part {
+                TemplatePart::Text(text) => rendered.push_str(text),
+                TemplatePart::Field { key, spec } => rendered.push_str(&spec.apply(self.values.get(key)))
+            }
+        }
+
+        rendered
     }
 }
 
+impl TUIBlock for CustomBlock {
+    // Only the listed channels are looked up, by their ACCRT names
+    fn update(&mut self, physics: &PhysicsData, graphics: &GraphicsData) {
+        resolve_channels(&mut self.values, &self.physics_keys, |name| physics.channel(name));
+        resolve_channels(&mut self.values, &self.graphics_keys, |name| graphics.channel(name));
+    }
+
+    fn display(&self, frame: &mut Frame<TermBackend>, area: Rect) {
+        let text: Vec<Spans> = self.render_template().lines()
+            .map(|line| Spans::from(String::from(line)))
+            .collect();
+
+        frame.render_widget(Paragraph::new(text).block(bordered_block(&self.title)), area);
+    }
+
+    fn init_statics(&mut self, _statics: &StaticData) { }
+
+    fn height(&self) -> u16 {
+        self.line_count + 2
+    }
+}
+
+fn resolve_channels(values: &mut HashMap<String, serde_json::Value>,
+                    keys: &[ChannelKey],
+                    lookup: impl Fn(&str) -> Option<serde_json::Value>) {
+    for channel in keys.iter() {
+        match channel.resolve(lookup(&channel.name)) {
+            Some(value) => values.insert(channel.key.clone(), value),
+            None => values.remove(&channel.key)
+        };
+    }
+}
+
+pub type BlockConstructor = fn() -> Box<dyn TUIBlock>;
+
+/// Every block that can be placed on the dashboard, by its options.yaml name
+pub fn block_registry() -> Vec<(&'static str, BlockConstructor)> {
+    vec![
+        ("tachometer",     || Box::new(Tachometer::new())),
+        ("lap_times",      || Box::new(LapTimes::new())),
+        ("tyre_temps",     || Box::new(TyreTemps::new())),
+        ("thermometer",    || Box::new(Thermometer::new())),
+        ("brake_temps",    || Box::new(BrakeTemps::new())),
+        ("tyre_pressures", || Box::new(TyrePressures::new())),
+        ("fuel_calculator", || Box::new(FuelCalculator::new())),
+        ("lap_history", || Box::new(LapHistory::new())),
+        ("link_quality", || Box::new(LinkQuality::new()))
+    ]
+}
+
+pub fn build_block(name: &str) -> Option<Box<dyn TUIBlock>> {
+    block_registry().into_iter()
+        .find(|(block_name, _)| *block_name == name)
+        .map(|(_, constructor)| constructor())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn fuel_calculator(safety_margin_laps: f64, max_fuel: f64) -> FuelCalculator {
+        let mut calculator = FuelCalculator::new();
+        calculator.safety_margin_laps = safety_margin_laps;
+        calculator.max_fuel = max_fuel;
+        calculator
+    }
+
+    // Crosses the line with the given fuel after a lap of the given time
+    fn cross_line(calculator: &mut FuelCalculator, completed_laps: u32, fuel: f64, lap_time_ms: i64) {
+        let physics = PhysicsData { fuel, ..Default::default() };
+        let graphics = GraphicsData {
+            completed_laps,
+            i_last_time: lap_time_ms,
+            session_time_left: 550000.0,
+            ..Default::default()
+        };
+        calculator.update(&physics, &graphics);
+    }
+
+    #[test]
+    fn averages_fuel_over_completed_laps() {
+        let mut calculator = fuel_calculator(1.0, 0.0);
+        calculator.fuel_x_lap = 4.0;
+        assert_eq!(calculator.average_usage(), 4.0);
+
+        // The lap the calculator joins in and the first crossing after it
+        // only set the baseline
+        cross_line(&mut calculator, 0, 62.0, 0);
+        cross_line(&mut calculator, 1, 60.0, 100000);
+        cross_line(&mut calculator, 2, 57.0, 100000);
+        cross_line(&mut calculator, 3, 55.0, 100000);
+        assert_eq!(calculator.average_usage(), 2.5);
+        assert_eq!(calculator.average_lap_time_ms(), Some(100000.0));
+    }
+
+    #[test]
+    fn skips_laps_jumped_over() {
+        let mut calculator = fuel_calculator(1.0, 0.0);
+        cross_line(&mut calculator, 0, 62.0, 0);
+        cross_line(&mut calculator, 1, 60.0, 100000);
+        cross_line(&mut calculator, 2, 57.0, 100000);
+
+        // Three laps' fuel at once, then a lap measured from the new baseline
+        cross_line(&mut calculator, 5, 48.0, 100000);
+        assert_eq!(calculator.lap_usage, VecDeque::from(vec![3.0]));
+        cross_line(&mut calculator, 6, 46.0, 100000);
+        assert_eq!(calculator.lap_usage, VecDeque::from(vec![3.0, 2.0]));
+    }
+
+    #[test]
+    fn ignores_refuelled_laps_and_resets_on_new_session() {
+        let mut calculator = fuel_calculator(1.0, 0.0);
+        cross_line(&mut calculator, 0, 62.0, 0);
+        cross_line(&mut calculator, 1, 60.0, 100000);
+        cross_line(&mut calculator, 2, 57.0, 100000);
+        cross_line(&mut calculator, 3, 90.0, 100000);
+        assert_eq!(calculator.lap_usage, VecDeque::from(vec![3.0]));
+
+        cross_line(&mut calculator, 0, 60.0, 0);
+        assert!(calculator.lap_usage.is_empty());
+        assert!(calculator.lap_times_ms.is_empty());
+    }
+
+    fn lap_sample(completed_laps: u32, sector_index: u32, last_sector_time: i64, fuel: f64)
+        -> (PhysicsData, GraphicsData) {
+        let physics = PhysicsData {
+            fuel,
+            tyre_temp: [78.0, 80.0, 80.0, 82.0],
+            wheels_pressure: [27.5; 4],
+            road_temp: 30.0,
+            ..Default::default()
+        };
+        let graphics = GraphicsData {
+            completed_laps,
+            current_sector_index: sector_index,
+            last_sector_time,
+            i_last_time: 100000,
+            is_valid_lap: true,
+            ..Default::default()
+        };
+        (physics, graphics)
+    }
+
+    fn lap_record(time_ms: i64, sectors: Vec<Option<i64>>, valid: bool) -> LapRecord {
+        LapRecord { lap: 1, time_ms, sectors, valid, fuel_used: 0.0, tyre_temp: 0.0, tyre_pressure: 0.0, track_temp: 0.0 }
+    }
+
+    #[test]
+    fn finishes_laps_with_the_last_sector_from_the_lap_time() {
+        let (physics, graphics) = lap_sample(3, 0, 0, 50.0);
+        let mut lap = LapInProgress::new(3, &physics, &graphics);
+        lap.sample(&physics, &graphics);
+        let (physics, graphics) = lap_sample(3, 1, 30000, 49.0);
+        lap.sample(&physics, &graphics);
+        let (physics, graphics) = lap_sample(3, 2, 35000, 48.0);
+        lap.sample(&physics, &graphics);
+
+        let record = lap.finish(4, 100000, 47.5);
+        assert_eq!(record.lap, 4);
+        assert_eq!(record.sectors, vec![Some(30000), Some(35000), Some(35000)]);
+        assert!(record.valid);
+        assert_eq!(record.fuel_used, 2.5);
+        assert_eq!(record.tyre_temp, 80.0);
+        assert_eq!(record.tyre_pressure, 27.5);
+        assert_eq!(record.track_temp, 30.0);
+    }
+
+    #[test]
+    fn leaves_the_last_sector_out_when_an_earlier_one_was_missed() {
+        // Joined part way through the second sector
+        let (physics, graphics) = lap_sample(3, 1, 0, 50.0);
+        let mut lap = LapInProgress::new(3, &physics, &graphics);
+        let (physics, mut graphics) = lap_sample(3, 2, 35000, 49.0);
+        graphics.is_valid_lap = false;
+        lap.sample(&physics, &graphics);
+
+        // Refuelling during the lap doesn't count as negative usage
+        let record = lap.finish(4, 100000, 80.0);
+        assert_eq!(record.sectors, vec![None, Some(35000), None]);
+        assert!(!record.valid);
+        assert_eq!(record.fuel_used, 0.0);
+    }
+
+    #[test]
+    fn drops_laps_that_ran_into_others() {
+        let mut history = LapHistory::new();
+        for completed_laps in [0, 1, 2, 5, 6] {
+            let (physics, graphics) = lap_sample(completed_laps, 0, 0, 50.0);
+            history.update(&physics, &graphics);
+        }
+
+        let laps: Vec<u32> = history.laps.iter().map(|lap| lap.lap).collect();
+        assert_eq!(laps, vec![2, 6]);
+    }
+
+    #[test]
+    fn only_valid_laps_set_personal_bests() {
+        let mut history = LapHistory::new();
+        history.laps = vec![
+            lap_record(101000, vec![Some(30000), Some(36000), Some(35000)], true),
+            lap_record(99000, vec![Some(29000), Some(35000), Some(35000)], false),
+            lap_record(100000, vec![Some(31000), Some(34000), None], true)
+        ];
+
+        assert_eq!(history.best_lap_ms(), Some(100000));
+        assert_eq!(history.best_sectors(), vec![Some(30000), Some(34000), Some(35000)]);
+
+        assert!(is_personal_best(&history.laps[2], 100000, history.best_lap_ms()));
+        assert!(!is_personal_best(&history.laps[1], 29000, Some(29000)));
+        assert!(!is_personal_best(&history.laps[0], 101000, history.best_lap_ms()));
+    }
+
+    #[test]
+    fn counts_laps_to_finish() {
+        let mut calculator = fuel_calculator(1.0, 0.0);
+        assert_eq!(calculator.laps_to_finish(), None);
+
+        // 550 s left at 100 s a lap ends during the sixth lap
+        calculator.lap_times_ms.push_back(100000);
+        calculator.session_time_left_ms = 550000.0;
+        assert_eq!(calculator.laps_to_finish(), Some(6.0));
+
+        calculator.session_time_left_ms = 0.0;
+        assert_eq!(calculator.laps_to_finish(), Some(0.0));
+
+        calculator.race_laps_left = Some(15);
+        assert_eq!(calculator.laps_to_finish(), Some(15.0));
+    }
+
+    #[test]
+    fn adds_enough_fuel_for_the_margin_but_no_more_than_fits() {
+        let mut calculator = fuel_calculator(1.0, 0.0);
+        calculator.lap_usage.push_back(3.0);
+        calculator.race_laps_left = Some(10);
+        calculator.fuel = 20.0;
+        assert_eq!(calculator.fuel_to_add(), Some(13.0));
+
+        calculator.fuel = 40.0;
+        assert_eq!(calculator.fuel_to_add(), Some(0.0));
+
+        calculator.race_laps_left = Some(40);
+        calculator.max_fuel = 100.0;
+        calculator.fuel = 80.0;
+        assert_eq!(calculator.fuel_to_add(), Some(20.0));
+
+        calculator.lap_usage.clear();
+        assert_eq!(calculator.fuel_to_add(), None);
+    }
+
+    #[test]
+    fn parses_channel_keys() {
+        let key = ChannelKey::parse("carCoordinates[0][2]").unwrap();
+        assert_eq!(key.name, "carCoordinates");
+        assert_eq!(key.indices, vec![0, 2]);
+
+        assert_eq!(ChannelKey::parse("fuel").unwrap().indices, Vec::<usize>::new());
+        assert!(ChannelKey::parse("").is_err());
+        assert!(ChannelKey::parse("[0]").is_err());
+        assert!(ChannelKey::parse("tyreTemp[x]").is_err());
+        assert!(ChannelKey::parse("tyreTemp[0").is_err());
+        assert!(ChannelKey::parse("tyreTemp[0]x").is_err());
+    }
+
+    #[test]
+    fn resolves_indexed_channels() {
+        let key = ChannelKey::parse("tyreTemp[2]").unwrap();
+        assert_eq!(key.resolve(Some(json!([80.0, 81.0, 82.0, 83.0]))), Some(json!(82.0)));
+        assert_eq!(key.resolve(Some(json!([80.0]))), None);
+        assert_eq!(key.resolve(None), None);
+    }
+
+    #[test]
+    fn parses_format_specs() {
+        let spec = FormatSpec::parse(">8.2").unwrap();
+        assert_eq!((spec.align, spec.width, spec.precision), (Some('>'), 8, Some(2)));
+
+        let spec = FormatSpec::parse("5").unwrap();
+        assert_eq!((spec.align, spec.width, spec.precision), (None, 5, None));
+
+        assert!(FormatSpec::parse("x").is_err());
+        assert!(FormatSpec::parse(".").is_err());
+    }
+
+    #[test]
+    fn applies_format_specs() {
+        assert_eq!(FormatSpec::parse(".1").unwrap().apply(Some(&json!(1.26))), "1.3");
+        assert_eq!(FormatSpec::parse("5").unwrap().apply(Some(&json!(42))), "   42");
+        assert_eq!(FormatSpec::parse("5").unwrap().apply(Some(&json!("ab"))), "ab   ");
+        assert_eq!(FormatSpec::parse("^5").unwrap().apply(None), "  -  ");
+    }
+
+    #[test]
+    fn parses_templates() {
+        let parts = parse_template("TC {tc:>2} {{raw}}").unwrap();
+        assert_eq!(parts.len(), 3);
+        assert!(matches!(&parts[0], TemplatePart::Text(text) if text == "TC "));
+        assert!(matches!(&parts[1], TemplatePart::Field { key, spec } if key == "tc" && spec.width == 2));
+        assert!(matches!(&parts[2], TemplatePart::Text(text) if text == " {raw}"));
+
+        assert!(parse_template("{unclosed").is_err());
+        assert!(parse_template("{fuel:bad}").is_err());
+    }
+}